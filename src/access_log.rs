@@ -0,0 +1,230 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage};
+use serde::Serialize;
+use std::io::Write;
+use std::time::{Instant, SystemTime};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::config::Config;
+use crate::{path_params, ratelimit, ResizeParams};
+
+// Чем заменяются адреса исходников при LOG_REDACT_URLS
+const REDACTED: &str = "<redacted>";
+// Параметры, в которых передаются адреса исходников
+const URL_PARAMS: [&str; 2] = ["url", "fallback_url"];
+
+// Формат строки журнала запросов
+#[derive(Clone, Copy, Debug)]
+pub enum LogFormat {
+    // Одна строка для чтения человеком
+    Text,
+    // Один JSON-объект на строку для сборщиков логов
+    Json,
+}
+
+// Откуда взят результат. Своего кэша у сервера нет: изображение либо обработано
+// для этого запроса, либо взято у такого же одновременного запроса.
+// Обработчики кладут значение в extensions запроса.
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStatus {
+    Miss,
+    Coalesced,
+}
+
+impl CacheStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Miss => "miss",
+            CacheStatus::Coalesced => "coalesced",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Entry {
+    timestamp: String,
+    client: Option<String>,
+    method: String,
+    path: String,
+    // Строка запроса; с LOG_REDACT_URLS без адресов исходников
+    params: String,
+    // Хост исходника из `url` или из пути /resize/<опции>/<url>
+    source_host: Option<String>,
+    status: u16,
+    format: Option<String>,
+    bytes: Option<u64>,
+    width: Option<u32>,
+    height: Option<u32>,
+    cache: Option<CacheStatus>,
+    duration_ms: u64,
+}
+
+// Middleware: по строке журнала на каждый запрос в stdout, отдельно от логов tracing.
+// Формат - LOG_FORMAT, адреса исходников скрываются при LOG_REDACT_URLS.
+pub async fn log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let Some(config) = req.app_data::<web::Data<Config>>().cloned() else {
+        return next.call(req).await;
+    };
+    let redact = config.log_redact_urls;
+    let source_host = source_url(&req).and_then(|url| host(&url));
+    let mut entry = Entry {
+        timestamp: OffsetDateTime::from(SystemTime::now())
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+        client: ratelimit::client_ip(req.request(), config.trust_forwarded_for)
+            .map(|ip| ip.to_string()),
+        method: req.method().to_string(),
+        path: redact_path(req.path(), redact),
+        params: params(req.query_string(), redact),
+        source_host,
+        status: 0,
+        format: None,
+        bytes: None,
+        width: None,
+        height: None,
+        cache: None,
+        duration_ms: 0,
+    };
+
+    let response = next.call(req).await;
+    match &response {
+        Ok(response) => {
+            entry.status = response.status().as_u16();
+            let headers = response.headers();
+            entry.format = headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            entry.bytes = match response.response().body().size() {
+                BodySize::Sized(size) => Some(size),
+                _ => None,
+            };
+            entry.width = header_number(headers, "X-Output-Width");
+            entry.height = header_number(headers, "X-Output-Height");
+            entry.cache = response
+                .request()
+                .extensions()
+                .get::<CacheStatus>()
+                .copied();
+        }
+        Err(err) => entry.status = err.as_response_error().status_code().as_u16(),
+    }
+    entry.duration_ms = started.elapsed().as_millis() as u64;
+    write(&entry, config.log_format);
+    response
+}
+
+fn write(entry: &Entry, format: LogFormat) {
+    let line = match format {
+        LogFormat::Json => serde_json::to_string(entry).unwrap_or_default(),
+        LogFormat::Text => {
+            let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+            format!(
+                "{} {} \"{} {}{}{}\" {} {} {}ms source={} format={} size={} cache={}",
+                entry.timestamp,
+                optional(entry.client.clone()),
+                entry.method,
+                entry.path,
+                if entry.params.is_empty() { "" } else { "?" },
+                entry.params,
+                entry.status,
+                optional(entry.bytes.map(|bytes| bytes.to_string())),
+                entry.duration_ms,
+                optional(entry.source_host.clone()),
+                optional(entry.format.clone()),
+                optional(
+                    entry
+                        .width
+                        .zip(entry.height)
+                        .map(|(width, height)| format!("{width}x{height}"))
+                ),
+                entry.cache.map_or("-", CacheStatus::as_str),
+            )
+        }
+    };
+    // Строка пишется целиком под блокировкой stdout, ошибки записи не важны
+    let _ = writeln!(std::io::stdout().lock(), "{line}");
+}
+
+// Путь для логов: в /resize/<опции>/<url> и /optimize/<опции>/<url> с `redact`
+// адрес скрывается
+pub fn redact_path(path: &str, redact: bool) -> String {
+    match path_params::options(path) {
+        Some(options) if redact => {
+            format!("{}{REDACTED}", &path[..path.len() - options.len()])
+        }
+        _ => path.to_string(),
+    }
+}
+
+// Адрес исходника: параметр `url` или хвост пути с опциями
+fn source_url(req: &ServiceRequest) -> Option<String> {
+    let pairs: Vec<(String, String)> =
+        serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+    if let Some((_, url)) = pairs.into_iter().find(|(name, _)| name == "url") {
+        return Some(url);
+    }
+    let path = path_params::options(req.path())?;
+    let mut params = ResizeParams::default();
+    path_params::apply(path, &mut params).ok()?;
+    params.url
+}
+
+// Адрес исходника для логов tracing: с LOG_REDACT_URLS остаётся только хост
+pub fn redact_url(url: &str, redact: bool) -> String {
+    if !redact {
+        return url.to_string();
+    }
+    match host(url) {
+        Some(host) => format!("{host}/{REDACTED}"),
+        None => REDACTED.to_string(),
+    }
+}
+
+fn host(url: &str) -> Option<String> {
+    reqwest::Url::parse(url)
+        .ok()?
+        .host_str()
+        .map(str::to_string)
+}
+
+// Строка запроса для журнала. data: URI сокращаются всегда, чтобы не писать
+// в журнал мегабайты base64; с `redact` адреса исходников скрываются целиком.
+fn params(query: &str, redact: bool) -> String {
+    let Ok(pairs) = serde_urlencoded::from_str::<Vec<(String, String)>>(query) else {
+        return if redact {
+            REDACTED.to_string()
+        } else {
+            query.to_string()
+        };
+    };
+    let pairs: Vec<(String, String)> = pairs
+        .into_iter()
+        .map(|(name, value)| {
+            let value = if !URL_PARAMS.contains(&name.as_str()) {
+                value
+            } else if redact {
+                REDACTED.to_string()
+            } else if value.starts_with("data:") {
+                "data:...".to_string()
+            } else {
+                value
+            };
+            (name, value)
+        })
+        .collect();
+    serde_urlencoded::to_string(pairs).unwrap_or_default()
+}
+
+fn header_number(headers: &HeaderMap, name: &str) -> Option<u32> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
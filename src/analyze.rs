@@ -0,0 +1,252 @@
+use actix_web::rt::time;
+use actix_web::{web, HttpResponse};
+use image::{imageops, DynamicImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::blurhash;
+use crate::config::{Config, QualityDefaults};
+use crate::error::AppError;
+use crate::failures::FailedFetches;
+use crate::memory::MemoryBudget;
+use crate::metadata;
+use crate::pool::ProcessingPool;
+use crate::{
+    apply_orientation, decode_rgba, encode, fetch_source, is_animated, open_reader,
+    parse_output_format, JpegOptions,
+};
+
+// До какого размера уменьшать изображение перед подсчётом основного цвета
+const DOMINANT_SAMPLE_SIZE: u32 = 64;
+// Сколько старших бит канала оставлять при группировке похожих цветов
+const DOMINANT_BITS: u32 = 4;
+// Компоненты BlurHash по горизонтали и вертикали, если `components` не задан
+const DEFAULT_BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+#[derive(Deserialize)]
+pub struct AnalyzeParams {
+    url: String,
+    // Формат и качество для оценки размера; по умолчанию формат исходника
+    // и DEFAULT_QUALITY_<ФОРМАТ>
+    format: Option<String>,
+    quality: Option<u8>,
+    // `blurhash` - добавить в ответ строку для размытого превью;
+    // `components` - число её компонент вида `4x3`, от 1 до 9 по каждой оси
+    placeholder: Option<String>,
+    components: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Estimate {
+    format: &'static str,
+    quality: u8,
+    bytes: usize,
+}
+
+#[derive(Serialize)]
+struct Analysis {
+    format: Option<&'static str>,
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+    animated: bool,
+    file_size: usize,
+    // Размер результата без ресайза в выбранном формате и качестве
+    estimated: Estimate,
+    // Самый частый цвет непрозрачных пикселей, `#rrggbb`
+    dominant_color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blurhash: Option<String>,
+}
+
+// Число компонент BlurHash вида `4x3`
+fn parse_components(value: &str) -> Option<(u32, u32)> {
+    let (x, y) = value.split_once(['x', 'X'])?;
+    let range = 1..=blurhash::MAX_COMPONENTS;
+    let (x, y) = (x.parse().ok()?, y.parse().ok()?);
+    (range.contains(&x) && range.contains(&y)).then_some((x, y))
+}
+
+// Метаданные исходника без пикселей в ответе: скачивание и декодирование
+// те же, что у /resize, оценка размера - настоящим кодированием
+pub async fn analyze(
+    config: web::Data<Config>,
+    client: web::Data<reqwest::Client>,
+    failures: web::Data<FailedFetches>,
+    pool: web::Data<ProcessingPool>,
+    query: web::Query<AnalyzeParams>,
+) -> Result<HttpResponse, AppError> {
+    let params = query.into_inner();
+    let output_format = params
+        .format
+        .as_deref()
+        .map(|value| {
+            parse_output_format(value).ok_or(AppError::InvalidParam("Unsupported output format"))
+        })
+        .transpose()?;
+    let blurhash_components = match params.placeholder.as_deref() {
+        None => None,
+        Some("blurhash") => Some(match params.components.as_deref() {
+            Some(value) => parse_components(value).ok_or(AppError::InvalidParam(
+                "Components must be XxY, each from 1 to 9",
+            ))?,
+            None => DEFAULT_BLURHASH_COMPONENTS,
+        }),
+        Some(_) => return Err(AppError::InvalidParam("Placeholder must be blurhash")),
+    };
+
+    if let Some(err) = failures.get(&params.url) {
+        return Err(err);
+    }
+    let source = fetch_source(&client, &config, &params.url)
+        .await
+        .inspect(|_| failures.record_success(&params.url))
+        .inspect_err(|err| failures.record(&params.url, err))?;
+
+    let max_input_pixels = config.max_input_pixels;
+    let default_quality = config.default_quality;
+    let timeout = Duration::from_secs(config.processing_timeout_seconds);
+    let memory = pool.memory();
+    let slot = pool.acquire().await;
+    let work = slot.run(move || {
+        analyze_image(
+            &source.bytes,
+            source.format_hint,
+            output_format,
+            params.quality,
+            default_quality,
+            max_input_pixels,
+            &memory,
+            blurhash_components,
+        )
+    });
+    let analysis = time::timeout(timeout, work)
+        .await
+        .map_err(|_| AppError::ProcessingTimeout)?
+        .map_err(|_| AppError::ProcessingFailed)??;
+    Ok(HttpResponse::Ok().json(analysis))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn analyze_image(
+    img_data: &[u8],
+    format_hint: Option<image::ImageFormat>,
+    output_format: Option<image::ImageFormat>,
+    quality: Option<u8>,
+    default_quality: QualityDefaults,
+    max_input_pixels: u64,
+    memory: &MemoryBudget,
+    blurhash_components: Option<(u32, u32)>,
+) -> Result<Analysis, AppError> {
+    let (width, height) = open_reader(img_data, format_hint)?
+        .into_dimensions()
+        .map_err(|err| AppError::DecodeFailed(err.to_string()))?;
+    if u64::from(width) * u64::from(height) > max_input_pixels {
+        return Err(AppError::TooLarge);
+    }
+    let _reservation = memory.reserve((width, height))?;
+    let reader = open_reader(img_data, format_hint)?;
+    let input_format = reader.format();
+    let (img, _) = decode_rgba(reader, img_data)?;
+    let has_alpha = img.pixels().any(|px| px[3] < u8::MAX);
+    let dominant_color = dominant_color(&img);
+    // Размер и превью в видимой ориентации, как X-Original-Width/Height у /resize
+    let orientation = metadata::exif_orientation(img_data);
+    let blurhash = blurhash_components.map(|(x, y)| match orientation {
+        1 => blurhash::encode(&img, x, y),
+        _ => blurhash::encode(&apply_orientation(img.clone(), orientation), x, y),
+    });
+    let (width, height) = match orientation {
+        5..=8 => (height, width),
+        _ => (width, height),
+    };
+
+    // Кодировщики, кроме PNG и JPEG, принимают только RGBA
+    let format = output_format
+        .or(input_format)
+        .unwrap_or(image::ImageFormat::Png);
+    let quality = quality
+        .unwrap_or_else(|| default_quality.for_format(format))
+        .clamp(default_quality.min, default_quality.max);
+    let (bytes, content_type) = encode(
+        &DynamicImage::ImageRgba8(img),
+        format,
+        f32::from(quality),
+        None,
+        JpegOptions::default(),
+        false,
+    )?;
+
+    Ok(Analysis {
+        format: input_format.map(|format| format.to_mime_type()),
+        width,
+        height,
+        has_alpha,
+        animated: is_animated(img_data, input_format),
+        file_size: img_data.len(),
+        estimated: Estimate {
+            format: content_type,
+            quality,
+            bytes: bytes.len(),
+        },
+        dominant_color,
+        blurhash,
+    })
+}
+
+// Пиксели группируются по старшим битам каналов, цвет - среднее самой большой группы
+fn dominant_color(img: &RgbaImage) -> Option<String> {
+    let sample = imageops::thumbnail(
+        img,
+        img.width().min(DOMINANT_SAMPLE_SIZE),
+        img.height().min(DOMINANT_SAMPLE_SIZE),
+    );
+    let shift = 8 - DOMINANT_BITS;
+    let mut groups: HashMap<[u8; 3], ([u64; 3], u64)> = HashMap::new();
+    for px in sample.pixels().filter(|px| px[3] >= 128) {
+        let key = [px[0] >> shift, px[1] >> shift, px[2] >> shift];
+        let (sum, count) = groups.entry(key).or_default();
+        for (total, channel) in sum.iter_mut().zip(&px.0[..3]) {
+            *total += u64::from(*channel);
+        }
+        *count += 1;
+    }
+    // При равных группах решает ключ, иначе ответ зависел бы от порядка обхода HashMap
+    let (_, (sum, count)) = groups
+        .into_iter()
+        .max_by_key(|(key, (_, count))| (*count, *key))?;
+    let [r, g, b] = sum.map(|total| (total / count) as u8);
+    Some(format!("#{r:02x}{g:02x}{b:02x}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    const RED: Rgba<u8> = Rgba([255, 0, 0, 255]);
+    const BLUE: Rgba<u8> = Rgba([0, 0, 255, 255]);
+
+    #[test]
+    fn tied_groups_pick_the_same_color() {
+        // Поровну красного и синего: побеждает больший ключ группы, то есть красный,
+        // при любом порядке обхода HashMap
+        for (first, second) in [(RED, BLUE), (BLUE, RED)] {
+            let img = RgbaImage::from_fn(4, 4, |x, _| if x < 2 { first } else { second });
+            for _ in 0..32 {
+                assert_eq!(dominant_color(&img).as_deref(), Some("#ff0000"));
+            }
+        }
+    }
+
+    #[test]
+    fn largest_group_wins() {
+        let img = RgbaImage::from_fn(4, 4, |x, y| if x == 0 && y < 3 { RED } else { BLUE });
+        assert_eq!(dominant_color(&img).as_deref(), Some("#0000ff"));
+        // Прозрачные пиксели не считаются, полностью прозрачное - без цвета
+        let img = RgbaImage::from_fn(4, 4, |x, _| if x == 0 { RED } else { Rgba([0, 0, 255, 0]) });
+        assert_eq!(dominant_color(&img).as_deref(), Some("#ff0000"));
+        assert_eq!(dominant_color(&RgbaImage::new(4, 4)), None);
+    }
+}
@@ -0,0 +1,197 @@
+use actix_web::http::header::Accept;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::{stream, StreamExt};
+use metrics::counter;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::coalesce::Inflight;
+use crate::concurrency::ConcurrencyLimit;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::failures::FailedFetches;
+use crate::pool::ProcessingPool;
+use crate::preset::Presets;
+use crate::ratelimit::RateLimiter;
+use crate::watermark::Watermarks;
+use crate::{
+    monitoring, prepare, process_url, save_data, signing, Output, Processed, ResizeParams,
+};
+
+// Сколько изображений можно запросить за раз
+const MAX_ITEMS: usize = 16;
+// Сколько элементов обрабатывается одновременно; каждый ещё проходит через PROCESSING_THREADS
+const PARALLELISM: usize = 4;
+// Маршрут, на который подписывается каждый элемент
+const SIGNED_PATH: &str = "/resize";
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ItemResult {
+    Ok {
+        status: u16,
+        content_type: &'static str,
+        width: u32,
+        height: u32,
+        bytes: usize,
+        // Результат в base64
+        data: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quality: Option<f32>,
+        fallback: bool,
+    },
+    Err {
+        status: u16,
+        error: &'static str,
+        message: String,
+    },
+}
+
+impl From<Result<Output, AppError>> for ItemResult {
+    fn from(result: Result<Output, AppError>) -> Self {
+        match result {
+            Ok(output) => ItemResult::Ok {
+                status: 200,
+                content_type: output.content_type,
+                width: output.output_size.0,
+                height: output.output_size.1,
+                bytes: output.bytes.len(),
+                data: BASE64.encode(&output.bytes),
+                quality: output.quality,
+                fallback: output.fallback,
+            },
+            Err(err) => ItemResult::Err {
+                status: err.status_code().as_u16(),
+                error: err.code(),
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+// Элемент - объект с теми же параметрами, что у GET /resize, значения - строки,
+// числа или true/false. Разбирается так же, как строка запроса.
+fn item_params(item: Value) -> Result<Vec<(String, String)>, AppError> {
+    let Value::Object(fields) = item else {
+        return Err(AppError::InvalidParam("Batch item must be an object"));
+    };
+    let mut params = Vec::with_capacity(fields.len());
+    for (name, value) in fields {
+        let value = match value {
+            Value::Null => continue,
+            Value::String(value) => value,
+            Value::Bool(value) => value.to_string(),
+            Value::Number(value) => value.to_string(),
+            Value::Array(_) | Value::Object(_) => {
+                return Err(AppError::InvalidParam(
+                    "Batch item values must be strings, numbers or booleans",
+                ))
+            }
+        };
+        params.push((name, value));
+    }
+    Ok(params)
+}
+
+// POST /optimize/batch: JSON-массив параметров по URL, в ответ массив результатов
+// в том же порядке. Ошибка одного элемента не прерывает остальные и описывается
+// в его результате. С SIGNING_SECRET каждый элемент несёт свой `sig`, посчитанный
+// как для GET /resize с теми же параметрами.
+// Каждый элемент стоит как отдельный запрос: токен лимита частоты (первый списан
+// middleware вместе с самим запросом) и место MAX_CONCURRENCY на время обработки.
+// Элементу, которому не хватило токена или места, достаётся ошибка 429 или 503.
+// Результаты по порядку складываются в BATCH_MAX_OUTPUT_BYTES; элемент, который
+// в него не помещается, получает ошибку 413, как и все следующие за ним.
+// Каждый аргумент - отдельный экстрактор actix
+#[allow(clippy::too_many_arguments)]
+pub async fn optimize_batch(
+    items: web::Json<Vec<Value>>,
+    req: HttpRequest,
+    accept: Option<web::Header<Accept>>,
+    config: web::Data<Config>,
+    client: web::Data<reqwest::Client>,
+    inflight: web::Data<Inflight<Processed>>,
+    failures: web::Data<FailedFetches>,
+    watermarks: web::Data<Watermarks>,
+    presets: web::Data<Presets>,
+    pool: web::Data<ProcessingPool>,
+    limiter: web::Data<RateLimiter>,
+    concurrency: web::Data<ConcurrencyLimit>,
+) -> Result<HttpResponse, AppError> {
+    let items = items.into_inner();
+    if items.is_empty() || items.len() > MAX_ITEMS {
+        return Err(AppError::InvalidBody(format!(
+            "batch must contain 1 to {MAX_ITEMS} items"
+        )));
+    }
+    let save_data = save_data(&req, &config);
+
+    let process = |(index, item): (usize, Value)| {
+        let (req, limiter, concurrency) = (req.clone(), limiter.clone(), concurrency.clone());
+        let accept = accept.clone();
+        let (config, client, inflight) = (config.clone(), client.clone(), inflight.clone());
+        let (failures, watermarks, pool) = (failures.clone(), watermarks.clone(), pool.clone());
+        let presets = presets.clone();
+        async move {
+            counter!(monitoring::REQUESTS_TOTAL).increment(1);
+            if index > 0 {
+                limiter.check(&req)?;
+            }
+            let _permit = concurrency.acquire().await?;
+            let pairs = item_params(item)?;
+            if let Some(secret) = config.signing_secret.as_deref() {
+                if !signing::is_valid(secret, SIGNED_PATH, &pairs) {
+                    return Err(AppError::InvalidSignature);
+                }
+            }
+            let query = serde_urlencoded::to_string(&pairs).unwrap_or_default();
+            let mut params = web::Query::<ResizeParams>::from_query(&query)
+                .map_err(|err| AppError::InvalidQuery(err.to_string()))?
+                .into_inner();
+            let requested_format = prepare(
+                &mut params,
+                accept,
+                save_data,
+                &config,
+                &watermarks,
+                &presets,
+            )?;
+            let url = params.url.take().ok_or(AppError::NoImage)?;
+            process_url(
+                url,
+                params,
+                requested_format,
+                config,
+                client,
+                inflight,
+                failures,
+                watermarks,
+                pool,
+            )
+            .await
+            .0
+        }
+    };
+    let mut output_bytes = 0usize;
+    let mut over_limit = false;
+    let results: Vec<ItemResult> = stream::iter(items.into_iter().enumerate())
+        .map(process)
+        .buffered(PARALLELISM)
+        .map(|result| {
+            let result = result.and_then(|output| {
+                output_bytes = output_bytes.saturating_add(output.bytes.len());
+                over_limit |= output_bytes > config.batch_max_output_bytes;
+                if over_limit {
+                    return Err(AppError::BatchTooLarge);
+                }
+                Ok(output)
+            });
+            ItemResult::from(result)
+        })
+        .collect()
+        .await;
+
+    Ok(HttpResponse::Ok().json(results))
+}
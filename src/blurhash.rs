@@ -0,0 +1,112 @@
+use image::{imageops, RgbaImage};
+use std::f32::consts::PI;
+
+// Кодирование BlurHash (https://blurha.sh): средний цвет и несколько
+// косинусных компонент в короткой строке base83, из которой фронтенд рисует
+// размытое превью до загрузки изображения
+
+const BASE83: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+// Размер копии, по которой считаются компоненты: больше деталей превью не передаёт
+const SAMPLE_SIZE: u32 = 32;
+// Допустимое число компонент по каждой оси
+pub const MAX_COMPONENTS: u32 = 9;
+
+// Строка BlurHash с `x` x `y` компонентами (каждое от 1 до MAX_COMPONENTS).
+// У BlurHash нет прозрачности, прозрачные области смешиваются с белым фоном.
+pub fn encode(img: &RgbaImage, x_components: u32, y_components: u32) -> String {
+    let sample = imageops::thumbnail(
+        img,
+        img.width().clamp(1, SAMPLE_SIZE),
+        img.height().clamp(1, SAMPLE_SIZE),
+    );
+    let (width, height) = sample.dimensions();
+    let linear: Vec<[f32; 3]> = sample
+        .pixels()
+        .map(|px| {
+            let alpha = f32::from(px[3]) / 255.0;
+            [0, 1, 2].map(|c| {
+                let value = srgb_to_linear(px[c]);
+                value * alpha + (1.0 - alpha)
+            })
+        })
+        .collect();
+
+    let mut factors = Vec::with_capacity((x_components * y_components) as usize);
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalization = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut sum = [0.0f32; 3];
+            for y in 0..height {
+                let basis_y = (PI * j as f32 * y as f32 / height as f32).cos();
+                for x in 0..width {
+                    let basis = (PI * i as f32 * x as f32 / width as f32).cos() * basis_y;
+                    let px = linear[(y * width + x) as usize];
+                    for c in 0..3 {
+                        sum[c] += basis * px[c];
+                    }
+                }
+            }
+            let scale = normalization / (width * height) as f32;
+            factors.push(sum.map(|value| value * scale));
+        }
+    }
+
+    let mut hash = String::new();
+    push_base83(&mut hash, (x_components - 1) + (y_components - 1) * 9, 1);
+    let (dc, ac) = factors.split_first().expect("at least one component");
+    let maximum = match ac
+        .iter()
+        .flatten()
+        .map(|value| value.abs())
+        .reduce(f32::max)
+    {
+        Some(actual) => {
+            let quantised = ((actual * 166.0 - 0.5).floor()).clamp(0.0, 82.0) as u32;
+            push_base83(&mut hash, quantised, 1);
+            (quantised + 1) as f32 / 166.0
+        }
+        None => {
+            push_base83(&mut hash, 0, 1);
+            1.0
+        }
+    };
+    let [r, g, b] = dc.map(linear_to_srgb);
+    push_base83(&mut hash, (r << 16) + (g << 8) + b, 4);
+    for factor in ac {
+        let [r, g, b] = factor.map(|value| {
+            let value = value / maximum;
+            (value.signum() * value.abs().sqrt() * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        push_base83(&mut hash, r * 19 * 19 + g * 19 + b, 2);
+    }
+    hash
+}
+
+fn push_base83(hash: &mut String, value: u32, length: u32) {
+    for digit in (0..length).rev() {
+        let index = value / 83u32.pow(digit) % 83;
+        hash.push(char::from(BASE83[index as usize]));
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = f32::from(value) / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    let encoded = if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (encoded * 255.0 + 0.5) as u32
+}
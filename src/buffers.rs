@@ -0,0 +1,71 @@
+use actix_web::web::Bytes;
+use std::sync::{Mutex, MutexGuard};
+
+// Наибольшая ёмкость буфера, который стоит хранить, и сколько байт всего держат
+// свободные буферы. Пул работает вне MAX_DECODED_BYTES, поэтому держит немного:
+// типичные результаты - десятки и сотни килобайт.
+const MAX_CAPACITY: usize = 1024 * 1024;
+const MAX_FREE_BYTES: usize = 4 * 1024 * 1024;
+
+struct Free {
+    buffers: Vec<Vec<u8>>,
+    // Сумма ёмкостей buffers
+    bytes: usize,
+}
+
+// Свободные буферы для результатов кодирования. Буфер возвращается сюда, когда
+// последний ответ с ним отправлен, и следующий результат пишется в уже выделенную память.
+static FREE: Mutex<Free> = Mutex::new(Free {
+    buffers: Vec::new(),
+    bytes: 0,
+});
+
+fn lock() -> MutexGuard<'static, Free> {
+    FREE.lock().unwrap_or_else(|poisoned| {
+        tracing::warn!("buffer pool mutex was poisoned, recovering");
+        FREE.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+// Пустой буфер, по возможности с уже выделенной памятью
+pub fn take() -> Vec<u8> {
+    let mut free = lock();
+    let buffer = free.buffers.pop().unwrap_or_default();
+    free.bytes -= buffer.capacity();
+    buffer
+}
+
+// Возвращает ненужный буфер, например вариант, не прошедший по max_bytes
+pub fn recycle(mut buffer: Vec<u8>) {
+    if buffer.capacity() == 0 || buffer.capacity() > MAX_CAPACITY {
+        return;
+    }
+    buffer.clear();
+    let mut free = lock();
+    if free.bytes + buffer.capacity() <= MAX_FREE_BYTES {
+        free.bytes += buffer.capacity();
+        free.buffers.push(buffer);
+    }
+}
+
+// Bytes поверх буфера: после отправки последнего ответа буфер вернётся в пул
+// со всей ёмкостью. Ужимать его под результат не стоит: shrink_to_fit копирует
+// ответ в новую память, а запас всё равно понадобится следующему результату.
+pub fn into_bytes(buffer: Vec<u8>) -> Bytes {
+    Bytes::from_owner(Pooled(buffer))
+}
+
+struct Pooled(Vec<u8>);
+
+impl AsRef<[u8]> for Pooled {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        recycle(std::mem::take(&mut self.0));
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+
+// Сколько хостов помним одновременно
+const MAX_HOSTS: usize = 10_000;
+// Через сколько пауз cooldown без запросов открытая цепь может быть забыта
+const STALE_COOLDOWNS: u32 = 10;
+
+// Состояние одного хоста; хосты без недавних ошибок в карте не хранятся
+struct HostCircuit {
+    // Ошибки подряд с начала окна
+    failures: u32,
+    window_start: Instant,
+    // Когда цепь разомкнулась; None - замкнута
+    opened_at: Option<Instant>,
+    // Когда после паузы пропущен пробный запрос
+    probe_at: Option<Instant>,
+}
+
+// Сколько хостов в каждом состоянии, для /metrics
+pub struct CircuitCounts {
+    pub open: usize,
+    pub half_open: usize,
+}
+
+// Предохранитель по хостам источников: после `threshold` ошибок подряд за `window`
+// запросы к хосту на `cooldown` сразу получают 502. Затем пропускается один пробный
+// запрос: успех замыкает цепь, ошибка размыкает её снова. Пробный запрос, который
+// не отчитался (например, клиент ушёл), через `cooldown` заменяется новым.
+pub struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    hosts: Mutex<HashMap<String, HostCircuit>>,
+}
+
+impl CircuitBreaker {
+    // CIRCUIT_BREAKER_THRESHOLD = 0 выключает предохранитель
+    pub fn new(config: &Config) -> Self {
+        CircuitBreaker {
+            threshold: config.circuit_breaker_threshold,
+            window: Duration::from_secs(config.circuit_breaker_window_seconds),
+            cooldown: Duration::from_secs(config.circuit_breaker_cooldown_seconds),
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, HostCircuit>> {
+        self.hosts.lock().unwrap_or_else(|poisoned| {
+            tracing::warn!("circuit breaker mutex was poisoned, recovering");
+            self.hosts.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    // Можно ли идти к хосту. Err - через сколько секунд цепь станет полуоткрытой.
+    pub fn check(&self, host: &str) -> Result<(), u64> {
+        if self.threshold == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut hosts = self.lock();
+        let Some(circuit) = hosts.get_mut(host) else {
+            return Ok(());
+        };
+        let Some(opened_at) = circuit.opened_at else {
+            return Ok(());
+        };
+        let retry_at = match circuit.probe_at {
+            // Пробный запрос уже идёт
+            Some(probe_at) if now.duration_since(probe_at) < self.cooldown => {
+                probe_at + self.cooldown
+            }
+            _ if now.duration_since(opened_at) < self.cooldown => opened_at + self.cooldown,
+            _ => {
+                tracing::info!(host, "circuit half-open, sending a probe request");
+                circuit.probe_at = Some(now);
+                return Ok(());
+            }
+        };
+        Err(retry_at.duration_since(now).as_secs_f64().ceil().max(1.0) as u64)
+    }
+
+    // Хост ответил: цепь замыкается, счётчик ошибок сбрасывается
+    pub fn record_success(&self, host: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        if let Some(circuit) = self.lock().remove(host) {
+            if circuit.opened_at.is_some() {
+                tracing::info!(host, "upstream recovered, circuit closed");
+            }
+        }
+    }
+
+    pub fn record_failure(&self, host: &str) {
+        if self.threshold == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut hosts = self.lock();
+        if !hosts.contains_key(host) && hosts.len() >= MAX_HOSTS {
+            self.evict(&mut hosts, now);
+        }
+        let circuit = hosts.entry(host.to_string()).or_insert(HostCircuit {
+            failures: 0,
+            window_start: now,
+            opened_at: None,
+            probe_at: None,
+        });
+        if circuit.opened_at.is_some() {
+            // Пробный или запоздавший запрос не прошёл: пауза начинается заново
+            circuit.opened_at = Some(now);
+            circuit.probe_at = None;
+            return;
+        }
+        if now.duration_since(circuit.window_start) >= self.window {
+            circuit.failures = 0;
+            circuit.window_start = now;
+        }
+        circuit.failures += 1;
+        if circuit.failures >= self.threshold {
+            tracing::warn!(
+                host,
+                failures = circuit.failures,
+                cooldown = ?self.cooldown,
+                "upstream keeps failing, circuit opened"
+            );
+            circuit.opened_at = Some(now);
+        }
+    }
+
+    // Освобождает место в карте: убирает закрытые цепи с истёкшим окном и открытые,
+    // к которым давно не было запросов (неудачный пробный запрос обновляет opened_at).
+    // Если таких нет - запись с самой давней активностью.
+    fn evict(&self, hosts: &mut HashMap<String, HostCircuit>, now: Instant) {
+        let (window, stale) = (self.window, self.cooldown * STALE_COOLDOWNS);
+        hosts.retain(|_, circuit| match circuit.opened_at {
+            Some(opened_at) => now.duration_since(opened_at) < stale,
+            None => now.duration_since(circuit.window_start) < window,
+        });
+        if hosts.len() >= MAX_HOSTS {
+            let oldest = hosts
+                .iter()
+                .min_by_key(|(_, circuit)| circuit.opened_at.unwrap_or(circuit.window_start))
+                .map(|(host, _)| host.clone());
+            if let Some(host) = oldest {
+                hosts.remove(&host);
+            }
+        }
+    }
+
+    pub fn counts(&self) -> CircuitCounts {
+        let now = Instant::now();
+        let hosts = self.lock();
+        let mut counts = CircuitCounts {
+            open: 0,
+            half_open: 0,
+        };
+        for opened_at in hosts.values().filter_map(|circuit| circuit.opened_at) {
+            if now.duration_since(opened_at) < self.cooldown {
+                counts.open += 1;
+            } else {
+                counts.half_open += 1;
+            }
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "img.example.com";
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker {
+            threshold: 3,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(30),
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Переносит размыкание и пробный запрос в прошлое, как будто прошло `by`
+    fn age(breaker: &CircuitBreaker, by: Duration) {
+        let mut hosts = breaker.lock();
+        let circuit = hosts.get_mut(HOST).unwrap();
+        circuit.window_start -= by;
+        circuit.opened_at = circuit.opened_at.map(|at| at - by);
+        circuit.probe_at = circuit.probe_at.map(|at| at - by);
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let breaker = breaker();
+        breaker.record_failure(HOST);
+        breaker.record_failure(HOST);
+        assert!(breaker.check(HOST).is_ok());
+        breaker.record_failure(HOST);
+        assert_eq!(breaker.check(HOST), Err(30));
+        assert!(breaker.check("other.example.com").is_ok());
+        assert_eq!(breaker.counts().open, 1);
+    }
+
+    #[test]
+    fn failures_outside_window_reset() {
+        let breaker = breaker();
+        breaker.record_failure(HOST);
+        breaker.record_failure(HOST);
+        age(&breaker, Duration::from_secs(61));
+        breaker.record_failure(HOST);
+        assert!(breaker.check(HOST).is_ok());
+    }
+
+    #[test]
+    fn half_open_probe() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            breaker.record_failure(HOST);
+        }
+        age(&breaker, Duration::from_secs(31));
+        assert_eq!(breaker.counts().half_open, 1);
+        // Пропускается один пробный запрос, остальные ждут его
+        assert!(breaker.check(HOST).is_ok());
+        assert_eq!(breaker.check(HOST), Err(30));
+
+        // Пробный запрос не прошёл: пауза заново
+        breaker.record_failure(HOST);
+        assert_eq!(breaker.check(HOST), Err(30));
+
+        // Следующий пробный запрос удался: цепь замкнута и забыта
+        age(&breaker, Duration::from_secs(31));
+        assert!(breaker.check(HOST).is_ok());
+        breaker.record_success(HOST);
+        assert!(breaker.check(HOST).is_ok());
+        assert!(breaker.lock().is_empty());
+    }
+
+    #[test]
+    fn lost_probe_is_replaced() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            breaker.record_failure(HOST);
+        }
+        age(&breaker, Duration::from_secs(31));
+        assert!(breaker.check(HOST).is_ok());
+        // Пробный запрос так и не отчитался
+        age(&breaker, Duration::from_secs(31));
+        assert!(breaker.check(HOST).is_ok());
+    }
+
+    #[test]
+    fn disabled_with_zero_threshold() {
+        let breaker = CircuitBreaker {
+            threshold: 0,
+            ..breaker()
+        };
+        for _ in 0..10 {
+            breaker.record_failure(HOST);
+        }
+        assert!(breaker.check(HOST).is_ok());
+        assert!(breaker.lock().is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Ключ фиксированной длины из частей: hex SHA-1, каждая часть с длиной впереди,
+// так что разделители внутри URL не склеивают разные наборы частей.
+// Исходные части пишутся в debug-лог, чтобы ключ можно было сопоставить с запросом.
+pub fn key(parts: &[&str]) -> String {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+    let key = format!("{:x}", hasher.finalize());
+    tracing::debug!(key, ?parts, "in-flight key");
+    key
+}
+
+// Идущая работа и сколько запросов её ждут; id отличает её от следующей
+// работы с тем же ключом
+struct Entry<T> {
+    id: u64,
+    shared: Shared<BoxFuture<'static, T>>,
+    waiters: usize,
+}
+
+type Pending<T> = HashMap<String, Entry<T>>;
+
+// Объединяет одинаковые одновременные запросы: первый выполняет работу,
+// остальные с тем же ключом ждут его результат
+pub struct Inflight<T: Clone> {
+    pending: Mutex<Pending<T>>,
+    next_id: AtomicU64,
+}
+
+impl<T: Clone + Send + Sync + 'static> Inflight<T> {
+    pub fn new() -> Self {
+        Inflight {
+            pending: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    // Отравленный мьютекс не должен ронять все последующие запросы: под ним
+    // только операции с HashMap, так что после паники карта остаётся целой
+    fn lock(&self) -> MutexGuard<'_, Pending<T>> {
+        self.pending.lock().unwrap_or_else(|poisoned| {
+            tracing::warn!("in-flight map mutex was poisoned, recovering");
+            self.pending.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    // Возвращает уже идущую работу для ключа или запускает `work`.
+    // Запись удаляется, когда работа завершилась, поэтому следующий запрос
+    // после этого выполняется заново. Если все ожидающие отвалились (клиенты
+    // закрыли соединения), запись удаляется вместе с работой и её данными.
+    // Второе значение - присоединился ли запрос к уже идущей работе.
+    pub async fn run<F>(self: Arc<Self>, key: String, work: F) -> (T, bool)
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let (shared, id, joined) = {
+            let mut pending = self.lock();
+            match pending.get_mut(&key) {
+                Some(entry) => {
+                    entry.waiters += 1;
+                    (entry.shared.clone(), entry.id, true)
+                }
+                None => {
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    let this = Arc::clone(&self);
+                    let done_key = key.clone();
+                    let shared = async move {
+                        let output = work.await;
+                        this.remove(&done_key, id);
+                        output
+                    }
+                    .boxed()
+                    .shared();
+                    pending.insert(
+                        key.clone(),
+                        Entry {
+                            id,
+                            shared: shared.clone(),
+                            waiters: 1,
+                        },
+                    );
+                    (shared, id, false)
+                }
+            }
+        };
+        let _waiter = Waiter {
+            inflight: &self,
+            key: &key,
+            id,
+        };
+        (shared.await, joined)
+    }
+
+    fn remove(&self, key: &str, id: u64) {
+        let mut pending = self.lock();
+        if pending.get(key).is_some_and(|entry| entry.id == id) {
+            pending.remove(key);
+        }
+    }
+}
+
+// Ожидающий запрос. Когда уходит последний, запись удаляется, и вместе с ней
+// последняя копия Shared: работа отменяется.
+struct Waiter<'a, T: Clone + Send + Sync + 'static> {
+    inflight: &'a Inflight<T>,
+    key: &'a str,
+    id: u64,
+}
+
+impl<T: Clone + Send + Sync + 'static> Drop for Waiter<'_, T> {
+    fn drop(&mut self) {
+        let mut pending = self.inflight.lock();
+        let Some(entry) = pending
+            .get_mut(self.key)
+            .filter(|entry| entry.id == self.id)
+        else {
+            return;
+        };
+        entry.waiters -= 1;
+        if entry.waiters == 0 {
+            // Работа отменяется после снятия блокировки: её drop тоже берёт блокировку
+            let entry = pending.remove(self.key);
+            drop(pending);
+            drop(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::atomic::AtomicBool;
+    use std::task::{Context, Poll};
+    use tokio::sync::oneshot;
+
+    // Отмечает, что работа отменена: её future сброшен
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn poll<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(
+            futures_util::task::noop_waker_ref(),
+        ))
+    }
+
+    #[test]
+    fn key_separates_parts() {
+        assert_eq!(key(&["a", "b"]), key(&["a", "b"]));
+        assert_ne!(key(&["ab", "c"]), key(&["a", "bc"]));
+        assert_ne!(key(&["a"]), key(&["a", ""]));
+    }
+
+    #[test]
+    fn joins_running_work() {
+        let inflight = Arc::new(Inflight::<u32>::new());
+        let (tx, rx) = oneshot::channel();
+        let mut first = Box::pin(
+            inflight
+                .clone()
+                .run("k".into(), async move { rx.await.unwrap() }),
+        );
+        let mut second = Box::pin(inflight.clone().run("k".into(), async { unreachable!() }));
+        assert!(poll(first.as_mut()).is_pending());
+        assert!(poll(second.as_mut()).is_pending());
+        tx.send(7).unwrap();
+        assert_eq!(poll(first.as_mut()), Poll::Ready((7, false)));
+        assert_eq!(poll(second.as_mut()), Poll::Ready((7, true)));
+        assert!(inflight.lock().is_empty());
+    }
+
+    #[test]
+    fn dropped_waiters_cancel_work() {
+        let inflight = Arc::new(Inflight::<u32>::new());
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(cancelled.clone());
+        let work = async move {
+            let _flag = flag;
+            std::future::pending::<u32>().await
+        };
+        let mut first = Box::pin(inflight.clone().run("k".into(), work));
+        let mut second = Box::pin(inflight.clone().run("k".into(), async { 0 }));
+        assert!(poll(first.as_mut()).is_pending());
+        assert!(poll(second.as_mut()).is_pending());
+
+        // Один ушёл: работа продолжается для второго
+        drop(first);
+        assert_eq!(inflight.lock()["k"].waiters, 1);
+        assert!(!cancelled.load(Ordering::SeqCst));
+
+        // Ушёл последний: запись удалена, работа отменена
+        drop(second);
+        assert!(inflight.lock().is_empty());
+        assert!(cancelled.load(Ordering::SeqCst));
+
+        // Следующий запрос с тем же ключом выполняет работу заново
+        let mut third = Box::pin(inflight.clone().run("k".into(), async { 5 }));
+        assert_eq!(poll(third.as_mut()), Poll::Ready((5, false)));
+    }
+}
@@ -0,0 +1,363 @@
+use image::RgbaImage;
+
+// Перевод пикселей из RGB-профиля исходника в sRGB. Поддерживаются матричные
+// профили (rXYZ/gXYZ/bXYZ и кривые rTRC/gTRC/bTRC): так устроены Display P3,
+// Adobe RGB и большинство профилей камер. LUT-профили и не-RGB пространства
+// не конвертируются.
+
+// Основные цвета sRGB в PCS (XYZ, D50), как в профиле sRGB IEC61966-2.1
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.436_074_7, 0.385_064_9, 0.143_080_4],
+    [0.222_504_5, 0.716_878_6, 0.060_616_9],
+    [0.013_932_2, 0.097_104_5, 0.714_173_3],
+];
+// Число шагов таблицы обратной гамма-коррекции sRGB
+const ENCODE_STEPS: usize = 4096;
+// Заголовок ICC перед таблицей тегов, и размер одной записи в ней
+const HEADER_SIZE: usize = 128;
+const TAG_ENTRY_SIZE: usize = 12;
+
+// Линейное значение канала для каждого 8-битного уровня
+type Curve = [f32; 256];
+type Matrix = [[f32; 3]; 3];
+
+// Переводит пиксели в sRGB на месте. false - профиль не поддерживается,
+// пиксели не тронуты и профиль стоит сохранить в результате.
+pub fn to_srgb(img: &mut RgbaImage, icc: &[u8]) -> bool {
+    let Some((curves, matrix)) = conversion(icc) else {
+        return false;
+    };
+    let encode: Vec<u8> = (0..ENCODE_STEPS)
+        .map(|step| srgb_encode(step as f32 / (ENCODE_STEPS - 1) as f32))
+        .collect();
+    for px in img.pixels_mut() {
+        let linear = [0, 1, 2].map(|c| curves[c][usize::from(px[c])]);
+        for (c, row) in matrix.iter().enumerate() {
+            let value = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+            let step = (value.clamp(0.0, 1.0) * (ENCODE_STEPS - 1) as f32).round() as usize;
+            px[c] = encode[step];
+        }
+    }
+    true
+}
+
+// Кривые каналов исходника и матрица из его линейного RGB в линейный sRGB
+fn conversion(icc: &[u8]) -> Option<([Curve; 3], Matrix)> {
+    if icc.get(16..20)? != b"RGB " {
+        return None;
+    }
+    // Записей не больше, чем помещается в профиль, даже если счётчик испорчен
+    let count = (read_u32(icc, HEADER_SIZE)? as usize).min(icc.len() / TAG_ENTRY_SIZE);
+    let tag = |signature: &[u8]| {
+        (0..count).find_map(|index| {
+            let entry = HEADER_SIZE + 4 + index * TAG_ENTRY_SIZE;
+            if icc.get(entry..entry + 4)? != signature {
+                return None;
+            }
+            let offset = read_u32(icc, entry + 4)? as usize;
+            let size = read_u32(icc, entry + 8)? as usize;
+            icc.get(offset..offset.checked_add(size)?)
+        })
+    };
+
+    let mut to_xyz = [[0.0; 3]; 3];
+    for (c, signature) in [b"rXYZ", b"gXYZ", b"bXYZ"].into_iter().enumerate() {
+        let xyz = tag(signature)?;
+        if xyz.get(..4)? != b"XYZ " {
+            return None;
+        }
+        for (row, values) in to_xyz.iter_mut().enumerate() {
+            values[c] = read_s15_fixed16(xyz, 8 + row * 4)?;
+        }
+    }
+    let curves = [
+        parse_curve(tag(b"rTRC")?)?,
+        parse_curve(tag(b"gTRC")?)?,
+        parse_curve(tag(b"bTRC")?)?,
+    ];
+    Some((curves, multiply(&invert(&SRGB_TO_XYZ)?, &to_xyz)))
+}
+
+// Кривая из тега curv (гамма или таблица) или para (параметрическая функция)
+fn parse_curve(data: &[u8]) -> Option<Curve> {
+    let mut curve = [0.0; 256];
+    match data.get(..4)? {
+        b"curv" => {
+            let points = read_u32(data, 8)? as usize;
+            let table = (0..points)
+                .map(|i| read_u16(data, 12 + i * 2))
+                .collect::<Option<Vec<u16>>>()?;
+            for (level, value) in curve.iter_mut().enumerate() {
+                let x = level as f32 / 255.0;
+                *value = match table.as_slice() {
+                    [] => x,
+                    // Одна точка - показатель гаммы в формате u8Fixed8
+                    [gamma] => x.powf(f32::from(*gamma) / 256.0),
+                    // Иначе таблица, между точками линейная интерполяция
+                    table => {
+                        let position = x * (table.len() - 1) as f32;
+                        let index = (position as usize).min(table.len() - 2);
+                        let fraction = position - index as f32;
+                        let low = f32::from(table[index]);
+                        let high = f32::from(table[index + 1]);
+                        (low + (high - low) * fraction) / 65535.0
+                    }
+                };
+            }
+        }
+        b"para" => {
+            let function = read_u16(data, 8)?;
+            let count = match function {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return None,
+            };
+            let p = (0..count)
+                .map(|i| read_s15_fixed16(data, 12 + i * 4))
+                .collect::<Option<Vec<f32>>>()?;
+            if function > 0 && p[1] == 0.0 {
+                return None;
+            }
+            // Все варианты сводятся к Y = (aX + b)^g + e при X >= d, иначе cX + f
+            let (g, a, b, c, d, e, f) = match function {
+                0 => (p[0], 1.0, 0.0, 0.0, 0.0, 0.0, 0.0),
+                1 => (p[0], p[1], p[2], 0.0, -p[2] / p[1], 0.0, 0.0),
+                2 => (p[0], p[1], p[2], 0.0, -p[2] / p[1], p[3], p[3]),
+                3 => (p[0], p[1], p[2], p[3], p[4], 0.0, 0.0),
+                _ => (p[0], p[1], p[2], p[3], p[4], p[5], p[6]),
+            };
+            for (level, value) in curve.iter_mut().enumerate() {
+                let x = level as f32 / 255.0;
+                *value = if x >= d {
+                    (a * x + b).max(0.0).powf(g) + e
+                } else {
+                    c * x + f
+                };
+            }
+        }
+        _ => return None,
+    }
+    for value in &mut curve {
+        *value = value.clamp(0.0, 1.0);
+    }
+    Some(curve)
+}
+
+// Гамма-коррекция sRGB для линейного значения от 0 до 1
+fn srgb_encode(linear: f32) -> u8 {
+    let value = if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+fn multiply(left: &Matrix, right: &Matrix) -> Matrix {
+    let mut out = [[0.0; 3]; 3];
+    for (row, values) in out.iter_mut().enumerate() {
+        for (col, value) in values.iter_mut().enumerate() {
+            *value = (0..3).map(|k| left[row][k] * right[k][col]).sum();
+        }
+    }
+    out
+}
+
+fn invert(m: &Matrix) -> Option<Matrix> {
+    let cofactor = |row: usize, col: usize| {
+        let (r1, r2) = ((row + 1) % 3, (row + 2) % 3);
+        let (c1, c2) = ((col + 1) % 3, (col + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let det: f32 = (0..3).map(|col| m[0][col] * cofactor(0, col)).sum();
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+    let mut out = [[0.0; 3]; 3];
+    for (row, values) in out.iter_mut().enumerate() {
+        for (col, value) in values.iter_mut().enumerate() {
+            // Обратная матрица - транспонированная матрица алгебраических дополнений
+            *value = cofactor(col, row) / det;
+        }
+    }
+    Some(out)
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+// Число s15Fixed16Number из ICC: знаковое, 16 бит дробной части
+fn read_s15_fixed16(data: &[u8], at: usize) -> Option<f32> {
+    let raw = i32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?);
+    Some(raw as f32 / 65536.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s15_fixed16(value: f32) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    fn curv(points: &[u16]) -> Vec<u8> {
+        let mut data = b"curv\0\0\0\0".to_vec();
+        data.extend_from_slice(&(points.len() as u32).to_be_bytes());
+        for point in points {
+            data.extend_from_slice(&point.to_be_bytes());
+        }
+        data
+    }
+
+    fn para(function: u16, params: &[f32]) -> Vec<u8> {
+        let mut data = b"para\0\0\0\0".to_vec();
+        data.extend_from_slice(&function.to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        for &param in params {
+            data.extend_from_slice(&s15_fixed16(param));
+        }
+        data
+    }
+
+    // Кривая sRGB в виде para типа 3
+    fn srgb_para() -> Vec<u8> {
+        para(3, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045])
+    }
+
+    fn xyz(value: [f32; 3]) -> Vec<u8> {
+        let mut data = b"XYZ \0\0\0\0".to_vec();
+        for component in value {
+            data.extend_from_slice(&s15_fixed16(component));
+        }
+        data
+    }
+
+    // Матричный RGB-профиль: столбцы `colorants` - XYZ основных цветов
+    fn profile(colorants: [[f32; 3]; 3], trc: &[u8]) -> Vec<u8> {
+        let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+            (b"rXYZ", xyz(colorants.map(|row| row[0]))),
+            (b"gXYZ", xyz(colorants.map(|row| row[1]))),
+            (b"bXYZ", xyz(colorants.map(|row| row[2]))),
+            (b"rTRC", trc.to_vec()),
+            (b"gTRC", trc.to_vec()),
+            (b"bTRC", trc.to_vec()),
+        ];
+        let mut icc = vec![0; HEADER_SIZE];
+        icc[16..20].copy_from_slice(b"RGB ");
+        icc.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        let mut offset = icc.len() + tags.len() * TAG_ENTRY_SIZE;
+        let mut data = Vec::new();
+        for (signature, tag) in &tags {
+            icc.extend_from_slice(*signature);
+            icc.extend_from_slice(&(offset as u32).to_be_bytes());
+            icc.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            offset += tag.len();
+            data.extend_from_slice(tag);
+        }
+        icc.extend(data);
+        icc
+    }
+
+    fn srgb_decode(level: usize) -> f32 {
+        let x = level as f32 / 255.0;
+        if x <= 0.04045 {
+            x / 12.92
+        } else {
+            ((x + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    fn convert(icc: &[u8], rgb: [u8; 3]) -> Option<[u8; 3]> {
+        let mut img = RgbaImage::from_pixel(1, 1, image::Rgba([rgb[0], rgb[1], rgb[2], 255]));
+        to_srgb(&mut img, icc).then(|| {
+            let px = img.get_pixel(0, 0);
+            [px[0], px[1], px[2]]
+        })
+    }
+
+    #[test]
+    fn gamma_curve() {
+        // Гамма 2.2 в u8Fixed8: 563 / 256 = 2.199
+        let curve = parse_curve(&curv(&[563])).unwrap();
+        for level in [0, 64, 128, 255] {
+            let expected = (level as f32 / 255.0).powf(563.0 / 256.0);
+            assert!((curve[level] - expected).abs() < 1e-5, "{level}");
+        }
+        // Пустая кривая - тождественная
+        let identity = parse_curve(&curv(&[])).unwrap();
+        assert!((identity[51] - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn table_curve() {
+        let curve = parse_curve(&curv(&[0, 16384, 65535])).unwrap();
+        assert_eq!(curve[0], 0.0);
+        assert_eq!(curve[255], 1.0);
+        // x = 0.2 - 40% пути до второй точки
+        assert!((curve[51] - 0.4 * 16384.0 / 65535.0).abs() < 1e-5);
+        // x = 0.8 - 60% пути между второй и третьей
+        let expected = (16384.0 + 0.6 * (65535.0 - 16384.0)) / 65535.0;
+        assert!((curve[204] - expected).abs() < 1e-5);
+    }
+
+    #[test]
+    fn parametric_curves() {
+        let gamma = parse_curve(&para(0, &[2.2])).unwrap();
+        assert!((gamma[128] - (128.0f32 / 255.0).powf(2.2)).abs() < 1e-4);
+
+        let srgb = parse_curve(&srgb_para()).unwrap();
+        for level in [0, 5, 10, 11, 64, 128, 200, 255] {
+            assert!((srgb[level] - srgb_decode(level)).abs() < 1e-4, "{level}");
+        }
+
+        // Неизвестный тип функции и a = 0 не поддерживаются
+        assert!(parse_curve(&para(5, &[1.0; 7])).is_none());
+        assert!(parse_curve(&para(1, &[2.2, 0.0, 0.0])).is_none());
+    }
+
+    #[test]
+    fn srgb_profile_keeps_pixels() {
+        let icc = profile(SRGB_TO_XYZ, &srgb_para());
+        for rgb in [[0, 0, 0], [255, 255, 255], [200, 100, 50], [10, 128, 250]] {
+            let out = convert(&icc, rgb).unwrap();
+            for c in 0..3 {
+                assert!(out[c].abs_diff(rgb[c]) <= 1, "{rgb:?} -> {out:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn display_p3_to_srgb() {
+        // Основные цвета Display P3, адаптированные к D50, как в профиле Apple
+        let p3 = [
+            [0.515_121, 0.291_977, 0.157_104],
+            [0.241_196, 0.692_245, 0.066_574],
+            [-0.001_053, 0.041_885, 0.784_073],
+        ];
+        let icc = profile(p3, &srgb_para());
+        // Значение из матрицы P3 -> sRGB для линейного света
+        let out = convert(&icc, [200, 100, 50]).unwrap();
+        for (c, expected) in [215u8, 93, 31].into_iter().enumerate() {
+            assert!(out[c].abs_diff(expected) <= 2, "{out:?}");
+        }
+        // Чистый красный P3 за пределами sRGB обрезается до чистого красного
+        assert_eq!(convert(&icc, [255, 0, 0]).unwrap(), [255, 0, 0]);
+    }
+
+    #[test]
+    fn profile_without_matrix_is_not_converted() {
+        let mut icc = profile(SRGB_TO_XYZ, &srgb_para());
+        // Портим подпись rXYZ: как у LUT-профиля, матрицы нет
+        let entry = HEADER_SIZE + 4;
+        icc[entry..entry + 4].copy_from_slice(b"A2B0");
+        assert_eq!(convert(&icc, [200, 100, 50]), None);
+    }
+}
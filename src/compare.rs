@@ -0,0 +1,258 @@
+use actix_web::rt::time;
+use actix_web::{web, HttpResponse};
+use image::{DynamicImage, Rgb, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::config::{Config, QualityDefaults};
+use crate::error::AppError;
+use crate::failures::FailedFetches;
+use crate::memory::MemoryBudget;
+use crate::metadata;
+use crate::pool::ProcessingPool;
+use crate::{
+    apply_orientation, decode_rgba, encode, fetch_source, flatten_onto, open_reader,
+    parse_output_format, JpegOptions,
+};
+
+// Сторона окна SSIM и шаг, с которым окно сдвигается
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STEP: u32 = 4;
+// Константы SSIM для 8-битных значений: (0.01 * 255)^2 и (0.03 * 255)^2
+const SSIM_C1: f64 = 6.5025;
+const SSIM_C2: f64 = 58.5225;
+// Сколько RGBA-копий исходника живёт одновременно: исходник и эталон на белом
+// фоне на время кодирования, затем эталон и декодированный результат, плюс
+// рабочая копия кодировщика или декодера
+const LIVE_FRAMES: u64 = 3;
+
+#[derive(Deserialize)]
+pub struct CompareParams {
+    url: String,
+    // Как у /analyze: по умолчанию формат исходника и DEFAULT_QUALITY_<ФОРМАТ>
+    format: Option<String>,
+    quality: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct Comparison {
+    format: &'static str,
+    quality: u8,
+    width: u32,
+    height: u32,
+    // Размер исходника и результата в байтах
+    original_bytes: usize,
+    bytes: usize,
+    // SSIM по яркости, 1 - не отличить
+    ssim: f64,
+    // PSNR по RGB в децибелах; null - изображения совпадают попиксельно
+    psnr: Option<f64>,
+}
+
+// Насколько результат кодирования отличается от исходника: исходник кодируется
+// без ресайза в выбранном формате и качестве, декодируется обратно и сравнивается
+// с декодированным исходником. Прозрачные области сравниваются на белом фоне.
+// AVIF декодировать нечем, для него сравнение недоступно.
+pub async fn compare(
+    config: web::Data<Config>,
+    client: web::Data<reqwest::Client>,
+    failures: web::Data<FailedFetches>,
+    pool: web::Data<ProcessingPool>,
+    query: web::Query<CompareParams>,
+) -> Result<HttpResponse, AppError> {
+    let params = query.into_inner();
+    let output_format = params
+        .format
+        .as_deref()
+        .map(|value| {
+            parse_output_format(value).ok_or(AppError::InvalidParam("Unsupported output format"))
+        })
+        .transpose()?;
+    if output_format == Some(image::ImageFormat::Avif) {
+        return Err(AppError::InvalidParam("AVIF output cannot be compared"));
+    }
+
+    if let Some(err) = failures.get(&params.url) {
+        return Err(err);
+    }
+    let source = fetch_source(&client, &config, &params.url)
+        .await
+        .inspect(|_| failures.record_success(&params.url))
+        .inspect_err(|err| failures.record(&params.url, err))?;
+
+    let max_input_pixels = config.max_input_pixels;
+    let default_quality = config.default_quality;
+    let timeout = Duration::from_secs(config.processing_timeout_seconds);
+    let memory = pool.memory();
+    let slot = pool.acquire().await;
+    let work = slot.run(move || {
+        compare_image(
+            &source.bytes,
+            source.format_hint,
+            output_format,
+            params.quality,
+            default_quality,
+            max_input_pixels,
+            &memory,
+        )
+    });
+    let comparison = time::timeout(timeout, work)
+        .await
+        .map_err(|_| AppError::ProcessingTimeout)?
+        .map_err(|_| AppError::ProcessingFailed)??;
+    Ok(HttpResponse::Ok().json(comparison))
+}
+
+fn compare_image(
+    img_data: &[u8],
+    format_hint: Option<image::ImageFormat>,
+    output_format: Option<image::ImageFormat>,
+    quality: Option<u8>,
+    default_quality: QualityDefaults,
+    max_input_pixels: u64,
+    memory: &MemoryBudget,
+) -> Result<Comparison, AppError> {
+    let (width, height) = open_reader(img_data, format_hint)?
+        .into_dimensions()
+        .map_err(|err| AppError::DecodeFailed(err.to_string()))?;
+    if u64::from(width) * u64::from(height) > max_input_pixels {
+        return Err(AppError::TooLarge);
+    }
+    let _reservation = memory.reserve_frames((width, height), LIVE_FRAMES)?;
+    let reader = open_reader(img_data, format_hint)?;
+    let input_format = reader.format();
+    let (img, _) = decode_rgba(reader, img_data)?;
+    // Сравнение в видимой ориентации, как у /analyze и /resize
+    let img = match metadata::exif_orientation(img_data) {
+        1 => img,
+        orientation => apply_orientation(img, orientation),
+    };
+    let (width, height) = img.dimensions();
+
+    let format = output_format
+        .or(input_format)
+        .unwrap_or(image::ImageFormat::Png);
+    if format == image::ImageFormat::Avif {
+        return Err(AppError::InvalidParam("AVIF output cannot be compared"));
+    }
+    let quality = quality
+        .unwrap_or_else(|| default_quality.for_format(format))
+        .clamp(default_quality.min, default_quality.max);
+    // JPEG получает исходник на белом фоне, как в /resize: он же и эталон,
+    // отдельная копия не нужна
+    let (input, reference) = match format {
+        image::ImageFormat::Jpeg => {
+            let mut img = img;
+            flatten_onto(&mut img, Rgb([u8::MAX; 3]));
+            (DynamicImage::ImageRgba8(img), None)
+        }
+        _ => {
+            let mut reference = img.clone();
+            flatten_onto(&mut reference, Rgb([u8::MAX; 3]));
+            (DynamicImage::ImageRgba8(img), Some(reference))
+        }
+    };
+    let (bytes, content_type) = encode(
+        &input,
+        format,
+        f32::from(quality),
+        None,
+        JpegOptions::default(),
+        false,
+    )?;
+    // Исходник больше не нужен, освобождаем его до декодирования результата
+    let reference = match reference {
+        Some(reference) => {
+            drop(input);
+            reference
+        }
+        None => input.into_rgba8(),
+    };
+    let mut decoded = image::load_from_memory_with_format(&bytes, format)
+        .map_err(|err| AppError::DecodeFailed(err.to_string()))?
+        .into_rgba8();
+    flatten_onto(&mut decoded, Rgb([u8::MAX; 3]));
+
+    Ok(Comparison {
+        format: content_type,
+        quality,
+        width,
+        height,
+        original_bytes: img_data.len(),
+        bytes: bytes.len(),
+        ssim: ssim(&reference, &decoded),
+        psnr: psnr(&reference, &decoded),
+    })
+}
+
+// PSNR по каналам RGB; None при нулевой ошибке
+fn psnr(a: &RgbaImage, b: &RgbaImage) -> Option<f64> {
+    let (sum, count) = a
+        .pixels()
+        .zip(b.pixels())
+        .flat_map(|(pa, pb)| (0..3).map(move |c| f64::from(pa[c]) - f64::from(pb[c])))
+        .fold((0.0, 0u64), |(sum, count), diff| {
+            (sum + diff * diff, count + 1)
+        });
+    let mse = sum / count.max(1) as f64;
+    (mse > 0.0).then(|| 10.0 * (255.0 * 255.0 / mse).log10())
+}
+
+// Средний SSIM по окнам SSIM_WINDOW x SSIM_WINDOW со сдвигом SSIM_STEP;
+// изображение меньше окна сравнивается целиком
+fn ssim(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    let (width, height) = a.dimensions();
+    let luma = |img: &RgbaImage| -> Vec<f64> {
+        img.pixels()
+            .map(|px| {
+                0.299 * f64::from(px[0]) + 0.587 * f64::from(px[1]) + 0.114 * f64::from(px[2])
+            })
+            .collect()
+    };
+    let (la, lb) = (luma(a), luma(b));
+    let (window_width, window_height) = (width.min(SSIM_WINDOW), height.min(SSIM_WINDOW));
+
+    let mut total = 0.0;
+    let mut windows = 0u64;
+    let mut y = 0;
+    while y + window_height <= height {
+        let mut x = 0;
+        while x + window_width <= width {
+            total += window_ssim(&la, &lb, width, (x, y), (window_width, window_height));
+            windows += 1;
+            x += SSIM_STEP;
+        }
+        y += SSIM_STEP;
+    }
+    total / windows.max(1) as f64
+}
+
+fn window_ssim(
+    a: &[f64],
+    b: &[f64],
+    stride: u32,
+    (x, y): (u32, u32),
+    (width, height): (u32, u32),
+) -> f64 {
+    let n = f64::from(width * height);
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for row in y..y + height {
+        let start = (row * stride + x) as usize;
+        for (va, vb) in a[start..start + width as usize]
+            .iter()
+            .zip(&b[start..start + width as usize])
+        {
+            sum_a += va;
+            sum_b += vb;
+            sum_aa += va * va;
+            sum_bb += vb * vb;
+            sum_ab += va * vb;
+        }
+    }
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+    let var_a = sum_aa / n - mean_a * mean_a;
+    let var_b = sum_bb / n - mean_b * mean_b;
+    let covariance = sum_ab / n - mean_a * mean_b;
+    ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+        / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1) * (var_a + var_b + SSIM_C2))
+}
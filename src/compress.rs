@@ -0,0 +1,50 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, ContentEncoding};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+
+use crate::config::Config;
+
+// Middleware под actix Compress: помечает ответы, которые сжимать не нужно.
+// Изображения (кроме SVG) Compress и так не трогает; здесь отсеиваются тела меньше
+// COMPRESSION_MIN_BYTES, где gzip почти ничего не даёт, и части файла (206):
+// Content-Range относится к несжатым байтам.
+pub async fn skip_small(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let min_bytes = req
+        .app_data::<web::Data<Config>>()
+        .map_or(0, |config| config.compression_min_bytes);
+    let mut response = next.call(req).await?;
+    let small = match response.response().body().size() {
+        BodySize::Sized(size) => size < min_bytes as u64,
+        BodySize::None => true,
+        BodySize::Stream => false,
+    };
+    let skip = small || response.status() == StatusCode::PARTIAL_CONTENT;
+    let headers = response.headers_mut();
+    if skip && !headers.contains_key(header::CONTENT_ENCODING) {
+        // Content-Encoding: identity - штатный способ отключить Compress для ответа
+        headers.insert(
+            header::CONTENT_ENCODING,
+            ContentEncoding::Identity.to_header_value(),
+        );
+    }
+    Ok(response)
+}
+
+// Middleware над actix Compress: убирает пометку identity, клиенту она не нужна
+pub async fn strip_identity(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mut response = next.call(req).await?;
+    let headers = response.headers_mut();
+    if headers.get(header::CONTENT_ENCODING) == Some(&ContentEncoding::Identity.to_header_value()) {
+        headers.remove(header::CONTENT_ENCODING);
+    }
+    Ok(response)
+}
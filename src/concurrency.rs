@@ -0,0 +1,72 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::rt::time;
+use actix_web::{web, Error};
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::config::Config;
+use crate::error::AppError;
+
+// Через сколько секунд советуем повторить отклонённый запрос
+const RETRY_AFTER_SECONDS: u64 = 1;
+
+// Что делать с запросом, когда все места заняты
+#[derive(Clone, Copy)]
+pub enum Overflow {
+    // Сразу 503
+    Reject,
+    // Ждать места не дольше заданного, затем 503
+    Queue(Duration),
+}
+
+// Предел одновременно обрабатываемых запросов. В отличие от PROCESSING_THREADS
+// учитывает весь запрос целиком, вместе со скачанным исходником в памяти.
+pub struct ConcurrencyLimit {
+    permits: Option<Semaphore>,
+    overflow: Overflow,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(config: &Config) -> Self {
+        ConcurrencyLimit {
+            // MAX_CONCURRENCY=0 - без ограничения
+            permits: (config.max_concurrency > 0).then(|| Semaphore::new(config.max_concurrency)),
+            overflow: config.concurrency_overflow,
+        }
+    }
+
+    // Место на время обработки; None - предел выключен
+    pub async fn acquire(&self) -> Result<Option<SemaphorePermit<'_>>, AppError> {
+        let Some(permits) = &self.permits else {
+            return Ok(None);
+        };
+        let permit = match self.overflow {
+            Overflow::Reject => permits.try_acquire().ok(),
+            Overflow::Queue(wait) => time::timeout(wait, permits.acquire())
+                .await
+                .ok()
+                .and_then(Result::ok),
+        };
+        match permit {
+            Some(permit) => Ok(Some(permit)),
+            None => {
+                tracing::warn!("concurrency limit reached, rejecting request");
+                Err(AppError::Overloaded(RETRY_AFTER_SECONDS))
+            }
+        }
+    }
+}
+
+// Middleware для дорогих маршрутов: держит место на всё время обработки запроса
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(limit) = req.app_data::<web::Data<ConcurrencyLimit>>().cloned() else {
+        return next.call(req).await;
+    };
+    let _permit = limit.acquire().await?;
+    next.call(req).await
+}
@@ -0,0 +1,840 @@
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::access_log::LogFormat;
+use crate::concurrency::Overflow;
+use crate::parse_output_format;
+use crate::preset::Presets;
+
+// Адрес, на котором сервер слушает по умолчанию
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:3001";
+// Предел размера исходника, скачиваемого по URL
+const DEFAULT_MAX_DOWNLOAD_BYTES: usize = 20 * 1024 * 1024;
+// Предел суммарного размера результатов одного POST /optimize/batch
+const DEFAULT_BATCH_MAX_OUTPUT_BYTES: usize = 32 * 1024 * 1024;
+// Качество по умолчанию для форматов с потерями, когда quality не задан.
+// Шкалы у кодировщиков разные: AVIF 60 на глаз близок к JPEG 80.
+const DEFAULT_QUALITY_JPEG: u8 = 80;
+const DEFAULT_QUALITY_WEBP: u8 = 80;
+const DEFAULT_QUALITY_AVIF: u8 = 60;
+// На сколько снижается quality для клиентов с Save-Data: on
+const DEFAULT_SAVE_DATA_QUALITY_REDUCTION: u8 = 20;
+// Сколько секунд может идти обработка одного изображения
+const DEFAULT_PROCESSING_TIMEOUT_SECONDS: u64 = 30;
+// Таймауты загрузки исходника: установка соединения и весь запрос целиком
+const DEFAULT_FETCH_CONNECT_TIMEOUT_SECONDS: u64 = 5;
+const DEFAULT_FETCH_TIMEOUT_SECONDS: u64 = 30;
+// Кэш DNS для источников: сколько секунд помнить адреса и неудачные разрешения имён,
+// и сколько имён хранить одновременно
+const DEFAULT_DNS_CACHE_TTL_SECONDS: u64 = 60;
+const DEFAULT_DNS_CACHE_NEGATIVE_TTL_SECONDS: u64 = 5;
+const DEFAULT_DNS_CACHE_MAX_ENTRIES: usize = 1024;
+// Попытки загрузки при обрыве соединения или 5xx; пауза удваивается с каждой
+const DEFAULT_FETCH_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_FETCH_RETRY_BASE_DELAY_MS: u64 = 100;
+// Наибольшая пауза перед первым повтором
+const MAX_FETCH_RETRY_BASE_DELAY_MS: u64 = 10_000;
+// Сколько секунд помнить, что источник ответил ошибкой
+const DEFAULT_NEGATIVE_CACHE_TTL_SECONDS: u64 = 30;
+// Предохранитель хостов: сколько ошибок подряд за окно размыкают цепь
+// и сколько секунд запросы к хосту не идут
+const DEFAULT_CIRCUIT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_CIRCUIT_BREAKER_WINDOW_SECONDS: u64 = 60;
+const DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECONDS: u64 = 30;
+// Сколько секунд клиенты и CDN могут кэшировать результат
+const DEFAULT_CACHE_MAX_AGE_SECONDS: u32 = 3600;
+// Наибольший max-age, который можно запросить параметром cache_ttl
+const DEFAULT_CACHE_TTL_MAX_SECONDS: u32 = 7 * 24 * 3600;
+// Сколько секунд после max-age кэши могут отдавать старую копию, обновляя её в фоне
+const DEFAULT_STALE_WHILE_REVALIDATE_SECONDS: u32 = 0;
+// Наибольшая сторона результата в пикселях
+const DEFAULT_MAX_OUTPUT_DIMENSION: u32 = 8192;
+// Больше пикселей в исходнике не декодируем: 100 мегапикселей - около 400 МБ в RGBA
+const DEFAULT_MAX_INPUT_PIXELS: u64 = 100_000_000;
+// Предел размера тела POST /optimize
+const DEFAULT_MAX_UPLOAD_BYTES: usize = 20 * 1024 * 1024;
+// Лимит запросов на клиента: по умолчанию выключен (0 в секунду)
+const DEFAULT_RATE_LIMIT_PER_SECOND: f64 = 0.0;
+const DEFAULT_RATE_LIMIT_BURST: u32 = 20;
+// Сколько клиентов лимитер помнит одновременно
+const DEFAULT_RATE_LIMIT_MAX_CLIENTS: usize = 10_000;
+// Памяти под декодированные изображения одновременно: по умолчанию без ограничения
+const DEFAULT_MAX_DECODED_BYTES: u64 = 0;
+// Одновременных запросов к /resize и /optimize: по умолчанию без ограничения
+const DEFAULT_MAX_CONCURRENCY: usize = 0;
+// Сколько миллисекунд запрос ждёт места в режиме queue
+const DEFAULT_CONCURRENCY_QUEUE_TIMEOUT_MS: u64 = 5000;
+// Ответы меньше этого размера не сжимаются: gzip почти ничего не даёт
+const DEFAULT_COMPRESSION_MIN_BYTES: usize = 1024;
+// User-Agent запросов к источникам: имя и версия сервиса
+const DEFAULT_UPSTREAM_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+// Значение UPSTREAM_REFERER, при котором Referer - корень сайта исходника
+const REFERER_ORIGIN: &str = "origin";
+// Сколько секунд ждать завершения запросов при остановке (как у actix по умолчанию)
+const DEFAULT_SHUTDOWN_GRACE_SECONDS: u64 = 30;
+
+// quality по умолчанию для каждого формата с потерями и пределы, в которые
+// зажимается любое итоговое quality
+#[derive(Clone, Copy)]
+pub struct QualityDefaults {
+    pub jpeg: u8,
+    pub webp: u8,
+    pub avif: u8,
+    pub min: u8,
+    pub max: u8,
+}
+
+impl QualityDefaults {
+    // Для PNG и прочих форматов без потерь значение ни на что не влияет
+    pub fn for_format(&self, format: image::ImageFormat) -> u8 {
+        match format {
+            image::ImageFormat::WebP => self.webp,
+            image::ImageFormat::Avif => self.avif,
+            _ => self.jpeg,
+        }
+    }
+
+    // quality в пределах MIN_QUALITY..=MAX_QUALITY
+    pub fn clamp(&self, quality: f32) -> f32 {
+        quality.clamp(f32::from(self.min), f32::from(self.max))
+    }
+}
+
+// Referer для запросов к источникам
+pub enum UpstreamReferer {
+    // Одно значение для всех источников
+    Fixed(HeaderValue),
+    // Корень сайта исходника (`https://cdn.example.com/`): так защита от хотлинка
+    // видит переход со своего же сайта
+    Origin,
+}
+
+// Настройки сервера, читаются при старте из переменных окружения и CONFIG_FILE
+pub struct Config {
+    // Адрес и порт для входящих соединений
+    pub listen_addr: SocketAddr,
+    // Отдельный адрес для /health, /ready и /metrics; None - они на listen_addr
+    pub admin_listen_addr: Option<SocketAddr>,
+    // Внешний адрес сервера для ссылок /srcset, например https://img.example.com;
+    // None - ссылки относительные
+    pub public_base_url: Option<String>,
+    // Хосты, с которых разрешено загружать изображения; None - любые публичные
+    pub allowed_hosts: Option<Vec<String>>,
+    // Источники (Origin), которым разрешены запросы из браузера, `*` - любым;
+    // None - заголовки CORS не выставляются
+    pub allowed_origins: Option<Vec<String>>,
+    // Максимальный размер скачиваемого исходника в байтах
+    pub max_download_bytes: usize,
+    // Сколько байт результатов (до base64) может вернуть один batch
+    pub batch_max_output_bytes: usize,
+    // Сколько изображений обрабатывается одновременно, остальные ждут в очереди
+    pub processing_threads: usize,
+    // quality, если его нет ни в запросе, ни в пресете
+    pub default_quality: QualityDefaults,
+    // Формат вывода, если нет ни `format`, ни подходящего Accept; None - формат исходника
+    pub default_format: Option<image::ImageFormat>,
+    // Снижение quality при Save-Data: on; 0 - заголовок не учитывается
+    pub save_data_quality_reduction: u8,
+    // Через сколько секунд обработки клиент получает 408
+    pub processing_timeout_seconds: u64,
+    // Сколько секунд ждать установки соединения с источником
+    pub fetch_connect_timeout_seconds: u64,
+    // Сколько секунд может длиться загрузка исходника целиком, включая тело
+    pub fetch_timeout_seconds: u64,
+    // Сколько секунд кэшировать адреса хоста источника; 0 - разрешать имя каждый раз
+    pub dns_cache_ttl_seconds: u64,
+    // Сколько секунд помнить, что имя не разрешилось; 0 - не помнить
+    pub dns_cache_negative_ttl_seconds: u64,
+    // Предел числа имён в кэше DNS
+    pub dns_cache_max_entries: usize,
+    // Сколько всего попыток загрузки делать; 1 - без повторов
+    pub fetch_retry_attempts: u32,
+    // Пауза перед первым повтором в миллисекундах, не больше 10 секунд
+    pub fetch_retry_base_delay_ms: u64,
+    // Сколько секунд повторные запросы по сломанному URL сразу получают ошибку; 0 - не помнить
+    pub negative_cache_ttl_seconds: u64,
+    // Сколько ошибок хоста подряд за окно размыкают цепь; 0 - предохранитель выключен
+    pub circuit_breaker_threshold: u32,
+    // Окно, в котором считаются ошибки подряд, в секундах
+    pub circuit_breaker_window_seconds: u64,
+    // Сколько секунд запросы к хосту с разомкнутой цепью сразу получают 502
+    pub circuit_breaker_cooldown_seconds: u64,
+    // max-age в Cache-Control ответов с изображением; 0 - no-cache
+    pub cache_max_age_seconds: u32,
+    // Потолок для cache_ttl из запроса
+    pub cache_ttl_max_seconds: u32,
+    // stale-while-revalidate в Cache-Control; 0 - не добавлять
+    pub stale_while_revalidate_seconds: u32,
+    // Наибольшие width и height результата; запрошенные сверх этого урезаются
+    pub max_output_dimension: u32,
+    // Предел ширины на высоту исходника, проверяется по заголовку до декодирования
+    pub max_input_pixels: u64,
+    // Максимальный размер изображения в теле POST /optimize в байтах
+    pub max_upload_bytes: usize,
+    // Сколько запросов в секунду разрешено одному IP; 0 - без ограничения
+    pub rate_limit_per_second: f64,
+    // Сколько запросов подряд IP может сделать сверх равномерного темпа
+    pub rate_limit_burst: u32,
+    // Предел числа IP, для которых хранится состояние лимита
+    pub rate_limit_max_clients: usize,
+    // Сколько байт могут занимать все декодированные изображения сразу; 0 - без ограничения
+    pub max_decoded_bytes: u64,
+    // Сколько запросов обрабатывается одновременно; 0 - без ограничения
+    pub max_concurrency: usize,
+    // Что делать с запросами сверх max_concurrency: reject или queue с таймаутом
+    pub concurrency_overflow: Overflow,
+    // User-Agent запросов к источникам
+    pub upstream_user_agent: HeaderValue,
+    // Referer запросов к источникам; None - не отправляется
+    pub upstream_referer: Option<UpstreamReferer>,
+    // Заголовки для запросов к источникам (например, Authorization); уходят только
+    // на хост URL исходника, не на хосты, куда он перенаправил.
+    // User-Agent и Referer отсюда главнее UPSTREAM_USER_AGENT и UPSTREAM_REFERER
+    pub upstream_headers: HeaderMap,
+    // Заголовки для отдельных хостов, в том же формате, что ALLOWED_HOSTS: `*.example.com`;
+    // главнее всех остальных, так задаются User-Agent и Referer для одного CDN
+    pub upstream_host_headers: Vec<(String, HeaderMap)>,
+    // Брать IP клиента из X-Forwarded-For (только за доверенным прокси)
+    pub trust_forwarded_for: bool,
+    // Секрет HMAC-подписи запросов; None - подпись не требуется
+    pub signing_secret: Option<String>,
+    // Каталог с водяными знаками, загружается при старте
+    pub watermark_dir: Option<PathBuf>,
+    // Встроенные пресеты плюс загруженные из JSON-файла PRESETS_FILE
+    pub presets: Arc<Presets>,
+    // Каталог для исходников вида file:///name.jpg, путь уже разрешён
+    // через canonicalize; None - file:// не принимается
+    pub local_asset_dir: Option<PathBuf>,
+    // Формат журнала запросов (access log): text или json
+    pub log_format: LogFormat,
+    // Не писать адреса исходников в журнал запросов, только хост
+    pub log_redact_urls: bool,
+    // Наименьший размер ответа для сжатия gzip/br/zstd; изображения не сжимаются
+    pub compression_min_bytes: usize,
+    // Время на завершение текущих запросов после SIGTERM/SIGINT
+    pub shutdown_grace_seconds: u64,
+}
+
+impl Config {
+    // Настройки из переменных окружения, под ними - из JSON-файла `config_file`
+    // (его ключи - имена тех же переменных), ниже всего - значения по умолчанию.
+    // Неразборчивое значение - ошибка: молча работать с другими настройками хуже,
+    // чем не запуститься. Ошибки собираются все сразу, а не до первой.
+    pub fn load(config_file: Option<&Path>) -> Result<Self, Vec<String>> {
+        let mut settings = Settings::new(config_file)?;
+
+        let listen_addr = settings
+            .get("LISTEN_ADDR")
+            .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.to_string());
+        let listen_addr = settings
+            .check(
+                listen_addr
+                    .trim()
+                    .parse()
+                    .map_err(|err| format!("invalid LISTEN_ADDR {listen_addr:?}: {err}")),
+            )
+            .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.parse().expect("valid default address"));
+        let admin_listen_addr = settings.get("ADMIN_LISTEN_ADDR").and_then(|addr| {
+            let parsed = addr
+                .trim()
+                .parse()
+                .map_err(|err| format!("invalid ADMIN_LISTEN_ADDR {addr:?}: {err}"))
+                .and_then(|parsed| {
+                    if parsed == listen_addr {
+                        Err(format!(
+                            "ADMIN_LISTEN_ADDR {parsed} is the same as LISTEN_ADDR"
+                        ))
+                    } else {
+                        Ok(parsed)
+                    }
+                });
+            settings.check(parsed)
+        });
+
+        let queue_timeout = Duration::from_millis(settings.parse_or(
+            "CONCURRENCY_QUEUE_TIMEOUT_MS",
+            DEFAULT_CONCURRENCY_QUEUE_TIMEOUT_MS,
+        ));
+        let concurrency_overflow = match settings.get("CONCURRENCY_MODE").as_deref() {
+            None | Some("reject") => Overflow::Reject,
+            Some("queue") => Overflow::Queue(queue_timeout),
+            Some(mode) => {
+                settings.error(format!(
+                    "invalid CONCURRENCY_MODE {mode:?}: expected reject or queue"
+                ));
+                Overflow::Reject
+            }
+        };
+
+        let log_format = match settings.get("LOG_FORMAT").as_deref() {
+            None | Some("text") => LogFormat::Text,
+            Some("json") => LogFormat::Json,
+            Some(format) => {
+                settings.error(format!(
+                    "invalid LOG_FORMAT {format:?}: expected text or json"
+                ));
+                LogFormat::Text
+            }
+        };
+
+        let default_format = settings.get("DEFAULT_FORMAT").and_then(|value| {
+            settings.check(parse_output_format(value.trim()).ok_or_else(|| {
+                format!("invalid DEFAULT_FORMAT {value:?}: expected jpeg, png, webp or avif")
+            }))
+        });
+
+        let (min_quality, max_quality) = match (
+            settings.parse_or("MIN_QUALITY", 1u8).clamp(1, 100),
+            settings.parse_or("MAX_QUALITY", 100u8).clamp(1, 100),
+        ) {
+            (min, max) if min > max => {
+                settings.error(format!(
+                    "MIN_QUALITY {min} is greater than MAX_QUALITY {max}"
+                ));
+                (1, 100)
+            }
+            range => range,
+        };
+
+        let public_base_url = settings.get("PUBLIC_BASE_URL").and_then(|value| {
+            let value = value.trim();
+            let parsed = reqwest::Url::parse(value)
+                .map_err(|err| format!("invalid PUBLIC_BASE_URL {value:?}: {err}"))
+                .and_then(|url| match url.scheme() {
+                    "http" | "https" if url.query().is_none() && url.fragment().is_none() => {
+                        Ok(value.trim_end_matches('/').to_string())
+                    }
+                    _ => Err(format!(
+                        "invalid PUBLIC_BASE_URL {value:?}: expected http(s) URL without query"
+                    )),
+                });
+            settings.check(parsed)
+        });
+        let presets_file = settings.get_path("PRESETS_FILE");
+        let presets = Presets::load(presets_file.as_deref()).unwrap_or_else(|errors| {
+            settings.errors.extend(errors);
+            Presets::default()
+        });
+        let local_asset_dir = settings.get_path("LOCAL_ASSET_DIR").and_then(|dir| {
+            settings.check(
+                fs::canonicalize(&dir)
+                    .map_err(|err| format!("invalid LOCAL_ASSET_DIR {dir:?}: {err}")),
+            )
+        });
+
+        let upstream_user_agent = settings
+            .get("UPSTREAM_USER_AGENT")
+            .and_then(|value| {
+                settings.check(
+                    HeaderValue::from_str(value.trim())
+                        .map_err(|_| format!("invalid UPSTREAM_USER_AGENT {value:?}")),
+                )
+            })
+            .unwrap_or_else(|| HeaderValue::from_static(DEFAULT_UPSTREAM_USER_AGENT));
+        let upstream_referer = settings.get("UPSTREAM_REFERER").and_then(|value| {
+            let value = value.trim();
+            if value.eq_ignore_ascii_case(REFERER_ORIGIN) {
+                return Some(UpstreamReferer::Origin);
+            }
+            settings.check(
+                HeaderValue::from_str(value)
+                    .map(UpstreamReferer::Fixed)
+                    .map_err(|_| format!("invalid UPSTREAM_REFERER {value:?}")),
+            )
+        });
+        let upstream_headers = settings
+            .get("UPSTREAM_HEADERS")
+            .and_then(|value| settings.check(parse_headers("UPSTREAM_HEADERS", &value)))
+            .unwrap_or_default();
+        let upstream_host_headers = settings
+            .get("UPSTREAM_HOST_HEADERS")
+            .and_then(|value| settings.check(parse_host_headers(&value)))
+            .unwrap_or_default();
+
+        let config = Config {
+            listen_addr,
+            admin_listen_addr,
+            public_base_url,
+            allowed_hosts: settings
+                .get("ALLOWED_HOSTS")
+                .map(|value| parse_list(&value))
+                .filter(|hosts| !hosts.is_empty()),
+            allowed_origins: settings
+                .get("ALLOWED_ORIGINS")
+                .map(|value| {
+                    parse_list(&value)
+                        .into_iter()
+                        .map(|origin| origin.trim_end_matches('/').to_string())
+                        .collect::<Vec<_>>()
+                })
+                .filter(|origins| !origins.is_empty()),
+            max_download_bytes: settings.parse_or("MAX_DOWNLOAD_BYTES", DEFAULT_MAX_DOWNLOAD_BYTES),
+            batch_max_output_bytes: settings
+                .parse_or("BATCH_MAX_OUTPUT_BYTES", DEFAULT_BATCH_MAX_OUTPUT_BYTES),
+            processing_threads: settings
+                .parse_or("PROCESSING_THREADS", default_processing_threads())
+                .max(1),
+            default_quality: QualityDefaults {
+                jpeg: settings
+                    .parse_or("DEFAULT_QUALITY_JPEG", DEFAULT_QUALITY_JPEG)
+                    .clamp(1, 100),
+                webp: settings
+                    .parse_or("DEFAULT_QUALITY_WEBP", DEFAULT_QUALITY_WEBP)
+                    .clamp(1, 100),
+                avif: settings
+                    .parse_or("DEFAULT_QUALITY_AVIF", DEFAULT_QUALITY_AVIF)
+                    .clamp(1, 100),
+                min: min_quality,
+                max: max_quality,
+            },
+            default_format,
+            save_data_quality_reduction: settings
+                .parse_or(
+                    "SAVE_DATA_QUALITY_REDUCTION",
+                    DEFAULT_SAVE_DATA_QUALITY_REDUCTION,
+                )
+                .min(100),
+            processing_timeout_seconds: settings
+                .parse_or(
+                    "PROCESSING_TIMEOUT_SECONDS",
+                    DEFAULT_PROCESSING_TIMEOUT_SECONDS,
+                )
+                .max(1),
+            fetch_connect_timeout_seconds: settings.parse_or(
+                "FETCH_CONNECT_TIMEOUT_SECONDS",
+                DEFAULT_FETCH_CONNECT_TIMEOUT_SECONDS,
+            ),
+            fetch_timeout_seconds: settings
+                .parse_or("FETCH_TIMEOUT_SECONDS", DEFAULT_FETCH_TIMEOUT_SECONDS),
+            dns_cache_ttl_seconds: settings
+                .parse_or("DNS_CACHE_TTL_SECONDS", DEFAULT_DNS_CACHE_TTL_SECONDS),
+            dns_cache_negative_ttl_seconds: settings.parse_or(
+                "DNS_CACHE_NEGATIVE_TTL_SECONDS",
+                DEFAULT_DNS_CACHE_NEGATIVE_TTL_SECONDS,
+            ),
+            dns_cache_max_entries: settings
+                .parse_or("DNS_CACHE_MAX_ENTRIES", DEFAULT_DNS_CACHE_MAX_ENTRIES),
+            fetch_retry_attempts: settings
+                .parse_or("FETCH_RETRY_ATTEMPTS", DEFAULT_FETCH_RETRY_ATTEMPTS)
+                .clamp(1, 10),
+            fetch_retry_base_delay_ms: settings
+                .parse_or(
+                    "FETCH_RETRY_BASE_DELAY_MS",
+                    DEFAULT_FETCH_RETRY_BASE_DELAY_MS,
+                )
+                .min(MAX_FETCH_RETRY_BASE_DELAY_MS),
+            negative_cache_ttl_seconds: settings.parse_or(
+                "NEGATIVE_CACHE_TTL_SECONDS",
+                DEFAULT_NEGATIVE_CACHE_TTL_SECONDS,
+            ),
+            circuit_breaker_threshold: settings.parse_or(
+                "CIRCUIT_BREAKER_THRESHOLD",
+                DEFAULT_CIRCUIT_BREAKER_THRESHOLD,
+            ),
+            circuit_breaker_window_seconds: settings.parse_or(
+                "CIRCUIT_BREAKER_WINDOW_SECONDS",
+                DEFAULT_CIRCUIT_BREAKER_WINDOW_SECONDS,
+            ),
+            circuit_breaker_cooldown_seconds: settings
+                .parse_or(
+                    "CIRCUIT_BREAKER_COOLDOWN_SECONDS",
+                    DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECONDS,
+                )
+                .max(1),
+            cache_max_age_seconds: settings
+                .parse_or("CACHE_MAX_AGE_SECONDS", DEFAULT_CACHE_MAX_AGE_SECONDS),
+            cache_ttl_max_seconds: settings
+                .parse_or("CACHE_TTL_MAX_SECONDS", DEFAULT_CACHE_TTL_MAX_SECONDS),
+            stale_while_revalidate_seconds: settings.parse_or(
+                "STALE_WHILE_REVALIDATE_SECONDS",
+                DEFAULT_STALE_WHILE_REVALIDATE_SECONDS,
+            ),
+            max_output_dimension: settings
+                .parse_or("MAX_OUTPUT_DIMENSION", DEFAULT_MAX_OUTPUT_DIMENSION)
+                .max(1),
+            max_input_pixels: settings
+                .parse_or("MAX_INPUT_PIXELS", DEFAULT_MAX_INPUT_PIXELS)
+                .max(1),
+            max_upload_bytes: settings.parse_or("MAX_UPLOAD_BYTES", DEFAULT_MAX_UPLOAD_BYTES),
+            rate_limit_per_second: settings
+                .parse_or("RATE_LIMIT_PER_SECOND", DEFAULT_RATE_LIMIT_PER_SECOND),
+            rate_limit_burst: settings.parse_or("RATE_LIMIT_BURST", DEFAULT_RATE_LIMIT_BURST),
+            rate_limit_max_clients: settings
+                .parse_or("RATE_LIMIT_MAX_CLIENTS", DEFAULT_RATE_LIMIT_MAX_CLIENTS),
+            max_decoded_bytes: settings.parse_or("MAX_DECODED_BYTES", DEFAULT_MAX_DECODED_BYTES),
+            max_concurrency: settings.parse_or("MAX_CONCURRENCY", DEFAULT_MAX_CONCURRENCY),
+            concurrency_overflow,
+            upstream_user_agent,
+            upstream_referer,
+            upstream_headers,
+            upstream_host_headers,
+            trust_forwarded_for: settings.parse_or("TRUST_FORWARDED_FOR", false),
+            signing_secret: settings
+                .get("SIGNING_SECRET")
+                .filter(|secret| !secret.is_empty()),
+            watermark_dir: settings.get_path("WATERMARK_DIR"),
+            presets: Arc::new(presets),
+            local_asset_dir,
+            log_format,
+            log_redact_urls: settings.parse_or("LOG_REDACT_URLS", false),
+            compression_min_bytes: settings
+                .parse_or("COMPRESSION_MIN_BYTES", DEFAULT_COMPRESSION_MIN_BYTES),
+            shutdown_grace_seconds: settings
+                .parse_or("SHUTDOWN_GRACE_SECONDS", DEFAULT_SHUTDOWN_GRACE_SECONDS),
+        };
+        settings.finish()?;
+        Ok(config)
+    }
+
+    // Печатает действующие настройки, чтобы было видно, что применилось
+    pub fn log(&self) {
+        tracing::info!("listen address: {}", self.listen_addr);
+        match self.admin_listen_addr {
+            Some(addr) => tracing::info!("admin address: {addr}"),
+            None => tracing::info!("admin endpoints on the listen address"),
+        }
+        match &self.public_base_url {
+            Some(url) => tracing::info!("public base URL: {url}"),
+            None => tracing::info!("public base URL: none, srcset links are relative"),
+        }
+        match &self.allowed_hosts {
+            Some(hosts) => tracing::info!("allowed hosts: {}", hosts.join(", ")),
+            None => tracing::info!("allowed hosts: any public host"),
+        }
+        match &self.local_asset_dir {
+            Some(dir) => tracing::info!("local assets: {}", dir.display()),
+            None => tracing::info!("local assets: off"),
+        }
+        match &self.allowed_origins {
+            Some(origins) => tracing::info!("CORS allowed origins: {}", origins.join(", ")),
+            None => tracing::info!("CORS disabled"),
+        }
+        tracing::info!("max download bytes: {}", self.max_download_bytes);
+        tracing::info!("max batch output bytes: {}", self.batch_max_output_bytes);
+        tracing::info!(
+            "processing: {} threads, timeout {}s",
+            self.processing_threads,
+            self.processing_timeout_seconds
+        );
+        tracing::info!(
+            "fetch timeouts: connect {}s, total {}s",
+            self.fetch_connect_timeout_seconds,
+            self.fetch_timeout_seconds
+        );
+        match self.dns_cache_ttl_seconds {
+            0 => tracing::info!("DNS cache: off"),
+            ttl => tracing::info!(
+                "DNS cache: {ttl}s, failed lookups {}s, up to {} hosts",
+                self.dns_cache_negative_ttl_seconds,
+                self.dns_cache_max_entries
+            ),
+        }
+        tracing::info!(
+            "fetch retries: {} attempts, base delay {}ms",
+            self.fetch_retry_attempts,
+            self.fetch_retry_base_delay_ms
+        );
+        tracing::info!(
+            "failed fetches remembered for {}s",
+            self.negative_cache_ttl_seconds
+        );
+        match self.circuit_breaker_threshold {
+            0 => tracing::info!("circuit breaker: off"),
+            threshold => tracing::info!(
+                "circuit breaker: {threshold} failures within {}s pause a host for {}s",
+                self.circuit_breaker_window_seconds,
+                self.circuit_breaker_cooldown_seconds
+            ),
+        }
+        tracing::info!(
+            "cache max-age: {}s (requests may set up to {}s), stale-while-revalidate: {}s",
+            self.cache_max_age_seconds,
+            self.cache_ttl_max_seconds,
+            self.stale_while_revalidate_seconds
+        );
+        tracing::info!("max output dimension: {}", self.max_output_dimension);
+        tracing::info!(
+            "default quality: jpeg {}, webp {}, avif {}",
+            self.default_quality.jpeg,
+            self.default_quality.webp,
+            self.default_quality.avif
+        );
+        if (self.default_quality.min, self.default_quality.max) != (1, 100) {
+            tracing::info!(
+                "quality limited to {}-{}",
+                self.default_quality.min,
+                self.default_quality.max
+            );
+        }
+        match self.default_format {
+            Some(format) => tracing::info!("default format: {format:?}"),
+            None => tracing::info!("default format: same as source"),
+        }
+        match self.save_data_quality_reduction {
+            0 => tracing::info!("Save-Data: ignored"),
+            reduction => tracing::info!("Save-Data: quality reduced by {reduction}"),
+        }
+        tracing::info!("max input pixels: {}", self.max_input_pixels);
+        tracing::info!("max upload bytes: {}", self.max_upload_bytes);
+        if self.rate_limit_per_second > 0.0 {
+            tracing::info!(
+                "rate limit: {}/s per client, burst {}, up to {} clients",
+                self.rate_limit_per_second,
+                self.rate_limit_burst,
+                self.rate_limit_max_clients
+            );
+        } else {
+            tracing::info!("rate limit: off");
+        }
+        match self.max_decoded_bytes {
+            0 => tracing::info!("max decoded bytes: unlimited"),
+            max => tracing::info!("max decoded bytes: {max}"),
+        }
+        match (self.max_concurrency, self.concurrency_overflow) {
+            (0, _) => tracing::info!("max concurrency: unlimited"),
+            (max, Overflow::Reject) => {
+                tracing::info!("max concurrency: {max}, excess requests rejected")
+            }
+            (max, Overflow::Queue(wait)) => tracing::info!(
+                "max concurrency: {max}, excess requests queued for up to {}ms",
+                wait.as_millis()
+            ),
+        }
+        tracing::info!(
+            "request signing: {}",
+            if self.signing_secret.is_some() {
+                "required"
+            } else {
+                "off"
+            }
+        );
+        tracing::info!(
+            "upstream User-Agent: {}",
+            self.upstream_user_agent.to_str().unwrap_or("<non-ASCII>")
+        );
+        match &self.upstream_referer {
+            Some(UpstreamReferer::Fixed(referer)) => tracing::info!(
+                "upstream Referer: {}",
+                referer.to_str().unwrap_or("<non-ASCII>")
+            ),
+            Some(UpstreamReferer::Origin) => tracing::info!("upstream Referer: source origin"),
+            None => tracing::info!("upstream Referer: none"),
+        }
+        // Только имена: значения - это учётные данные
+        if !self.upstream_headers.is_empty() {
+            tracing::info!("upstream headers: {}", header_names(&self.upstream_headers));
+        }
+        for (host, headers) in &self.upstream_host_headers {
+            tracing::info!("upstream headers for {host}: {}", header_names(headers));
+        }
+        tracing::info!("trust X-Forwarded-For: {}", self.trust_forwarded_for);
+        tracing::info!(
+            "access log: {:?}{}",
+            self.log_format,
+            if self.log_redact_urls {
+                ", source URLs redacted"
+            } else {
+                ""
+            }
+        );
+        tracing::info!(
+            "response compression: bodies from {} bytes, images excluded",
+            self.compression_min_bytes
+        );
+        tracing::info!("shutdown grace period: {}s", self.shutdown_grace_seconds);
+    }
+}
+
+// Значения настроек: переменная окружения, если задана и не пуста, иначе ключ
+// CONFIG_FILE. Ошибки разбора копятся в `errors`.
+struct Settings {
+    file: HashMap<String, String>,
+    // Имена, которые спрашивал Config::load: остальные ключи файла - опечатки
+    known: HashSet<&'static str>,
+    errors: Vec<String>,
+}
+
+impl Settings {
+    fn new(config_file: Option<&Path>) -> Result<Self, Vec<String>> {
+        let file = match config_file {
+            Some(path) => read_config_file(path).map_err(|err| vec![err])?,
+            None => HashMap::new(),
+        };
+        Ok(Settings {
+            file,
+            known: HashSet::new(),
+            errors: Vec::new(),
+        })
+    }
+
+    fn get(&mut self, name: &'static str) -> Option<String> {
+        self.known.insert(name);
+        env::var(name)
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+            .or_else(|| self.file.get(name).cloned())
+    }
+
+    fn get_path(&mut self, name: &'static str) -> Option<PathBuf> {
+        self.get(name).map(PathBuf::from)
+    }
+
+    // Значение или `default`, если настройка не задана; неразборчивое - ошибка
+    fn parse_or<T: FromStr>(&mut self, name: &'static str, default: T) -> T
+    where
+        T::Err: fmt::Display,
+    {
+        let Some(value) = self.get(name) else {
+            return default;
+        };
+        match value.trim().parse() {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                self.error(format!("invalid {name} {value:?}: {err}"));
+                default
+            }
+        }
+    }
+
+    fn check<T>(&mut self, result: Result<T, String>) -> Option<T> {
+        result.map_err(|err| self.error(err)).ok()
+    }
+
+    fn error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    fn finish(mut self) -> Result<(), Vec<String>> {
+        let mut unknown: Vec<&String> = self
+            .file
+            .keys()
+            .filter(|name| !self.known.contains(name.as_str()))
+            .collect();
+        unknown.sort();
+        let unknown: Vec<String> = unknown
+            .into_iter()
+            .map(|name| format!("unknown setting {name:?} in config file"))
+            .collect();
+        self.errors.extend(unknown);
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+// JSON-объект {"ИМЯ_ПЕРЕМЕННОЙ": значение}. Числа и true/false можно писать
+// без кавычек, списки (ALLOWED_HOSTS) - массивом, заголовки - объектом.
+fn read_config_file(path: &Path) -> Result<HashMap<String, String>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("cannot read config file {}: {err}", path.display()))?;
+    let values: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&text)
+        .map_err(|err| format!("invalid config file {}: {err}", path.display()))?;
+    values
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                serde_json::Value::Array(items) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        serde_json::Value::String(item) => Ok(item),
+                        serde_json::Value::Number(item) => Ok(item.to_string()),
+                        _ => Err(format!(
+                            "invalid {name} in config file: expected a list of strings"
+                        )),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(","),
+                serde_json::Value::Null => {
+                    return Err(format!("invalid {name} in config file: null"))
+                }
+                // Числа, true/false и объекты заголовков - в том виде, в котором их
+                // ждёт разбор переменной окружения
+                value => value.to_string(),
+            };
+            Ok((name, value))
+        })
+        .collect()
+}
+
+// По одному потоку обработки на ядро
+fn default_processing_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|threads| threads.get())
+        .unwrap_or(1)
+}
+
+// JSON-объект {"Имя": "значение"}. Значения помечаются как секретные,
+// чтобы не попасть в отладочный вывод.
+fn parse_headers(name: &str, value: &str) -> Result<HeaderMap, String> {
+    let pairs: HashMap<String, String> =
+        serde_json::from_str(value).map_err(|err| format!("invalid {name}: {err}"))?;
+    headers_from(name, pairs)
+}
+
+fn headers_from(name: &str, pairs: HashMap<String, String>) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for (header, value) in pairs {
+        let header = HeaderName::from_bytes(header.as_bytes())
+            .map_err(|_| format!("invalid {name}: bad header name {header:?}"))?;
+        let mut value = HeaderValue::from_str(&value)
+            .map_err(|_| format!("invalid {name}: bad value for {header}"))?;
+        value.set_sensitive(true);
+        headers.insert(header, value);
+    }
+    Ok(headers)
+}
+
+// JSON-объект {"хост": {"Имя": "значение"}}. Порядок ключей JSON-объекта не
+// сохраняется, поэтому хосты упорядочиваются так, чтобы первое совпадение было
+// самым точным: сначала имена без шаблона, затем шаблоны от длинных к коротким.
+fn parse_host_headers(value: &str) -> Result<Vec<(String, HeaderMap)>, String> {
+    let hosts: HashMap<String, HashMap<String, String>> = serde_json::from_str(value)
+        .map_err(|err| format!("invalid UPSTREAM_HOST_HEADERS: {err}"))?;
+    let mut hosts = hosts
+        .into_iter()
+        .map(|(host, pairs)| {
+            Ok((
+                host.trim().to_ascii_lowercase(),
+                headers_from("UPSTREAM_HOST_HEADERS", pairs)?,
+            ))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    hosts.sort_by(|(left, _), (right, _)| {
+        (left.starts_with("*."), Reverse(left.len()), left).cmp(&(
+            right.starts_with("*."),
+            Reverse(right.len()),
+            right,
+        ))
+    });
+    Ok(hosts)
+}
+
+fn header_names(headers: &HeaderMap) -> String {
+    headers
+        .keys()
+        .map(HeaderName::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Список через запятую, без пустых элементов и в нижнем регистре
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_ascii_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
@@ -0,0 +1,108 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+
+use crate::config::Config;
+
+// Методы, которые принимают маршруты сервера
+const ALLOW_METHODS: &str = "GET, HEAD, POST, OPTIONS";
+// Собственные заголовки ответа, которые скрипт на странице может прочитать
+const EXPOSE_HEADERS: &str =
+    "Retry-After, Content-Range, X-Quality-Used, X-Image-Complexity, X-Image-Warning, \
+    X-Fallback, X-Optimized, X-Bytes-Saved, X-Original-Width, X-Original-Height, X-Output-Width, \
+    X-Output-Height";
+// Сколько секунд браузер может помнить ответ на preflight
+const PREFLIGHT_MAX_AGE: &str = "86400";
+
+// Значение Access-Control-Allow-Origin для Origin запроса, None - источник не разрешён
+fn allow_origin(allowed: &[String], origin: &HeaderValue) -> Option<HeaderValue> {
+    if allowed.iter().any(|item| item == "*") {
+        return Some(HeaderValue::from_static("*"));
+    }
+    let value = origin.to_str().ok()?.trim_end_matches('/');
+    allowed
+        .iter()
+        .any(|item| item.eq_ignore_ascii_case(value))
+        .then(|| origin.clone())
+}
+
+// Middleware: при заданном ALLOWED_ORIGINS отвечает на preflight и добавляет
+// Access-Control-Allow-Origin для разрешённых источников, в том числе к ошибкам.
+// Без ALLOWED_ORIGINS заголовки CORS не выставляются.
+pub async fn handle(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let config = req.app_data::<web::Data<Config>>().cloned();
+    let Some(allowed) = config
+        .as_ref()
+        .and_then(|config| config.allowed_origins.as_deref())
+    else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    // Со списком источников ответ зависит от Origin, даже если его нет или он
+    // не разрешён: кэши не должны отдавать ответ без CORS другому источнику
+    let vary_origin = !allowed.iter().any(|item| item == "*");
+    let allow = req
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|origin| allow_origin(allowed, origin));
+
+    if let Some(allow) = allow.clone().filter(|_| {
+        req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }) {
+        let mut response = HttpResponse::NoContent();
+        response
+            .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, allow))
+            .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, ALLOW_METHODS))
+            .insert_header((header::ACCESS_CONTROL_MAX_AGE, PREFLIGHT_MAX_AGE));
+        if let Some(headers) = req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .cloned()
+        {
+            response.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, headers));
+        }
+        if vary_origin {
+            response.insert_header((header::VARY, "Origin"));
+        }
+        return Ok(req.into_response(response.finish()).map_into_right_body());
+    }
+
+    // Ошибки внутренних middleware (429, 403, 503) тоже должны быть видны странице.
+    // Запрос здесь не клонируется: маршрутизатору нужна единственная ссылка на него.
+    match next.call(req).await {
+        Ok(mut res) => {
+            add_headers(res.headers_mut(), allow, vary_origin);
+            Ok(res.map_into_left_body())
+        }
+        Err(err) => {
+            let mut response = err.error_response();
+            add_headers(response.headers_mut(), allow, vary_origin);
+            Err(InternalError::from_response(err, response).into())
+        }
+    }
+}
+
+fn add_headers(headers: &mut HeaderMap, allow: Option<HeaderValue>, vary_origin: bool) {
+    if let Some(allow) = allow {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow);
+        headers.insert(
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::from_static(EXPOSE_HEADERS),
+        );
+    }
+    if vary_origin {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
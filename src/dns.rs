@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Результат разрешения имени: адреса или текст ошибки (io::Error не клонируется)
+type Lookup = Result<Vec<SocketAddr>, String>;
+
+// Кэш DNS для загрузки исходников: изображения обычно идут с нескольких CDN,
+// и повторный запрос к тому же хосту обходится без разрешения имени.
+// Хранятся сырые адреса, проверка на публичность делается при каждом обращении.
+pub struct DnsCache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, (Instant, Lookup)>>,
+}
+
+impl DnsCache {
+    // ttl = 0 выключает кэш целиком, negative_ttl = 0 - только запоминание неудач
+    pub fn new(ttl: Duration, negative_ttl: Duration, max_entries: usize) -> Self {
+        DnsCache {
+            ttl,
+            negative_ttl,
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, (Instant, Lookup)>> {
+        self.entries.lock().unwrap_or_else(|poisoned| {
+            tracing::warn!("DNS cache mutex was poisoned, recovering");
+            self.entries.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    fn ttl_for(&self, lookup: &Lookup) -> Duration {
+        match lookup {
+            Ok(_) => self.ttl,
+            Err(_) => self.negative_ttl,
+        }
+    }
+
+    // Адреса имени из кэша или от системного резолвера
+    pub async fn lookup(&self, name: &str) -> io::Result<Vec<SocketAddr>> {
+        let name = name.to_ascii_lowercase();
+        if let Some(lookup) = self.get(&name) {
+            return lookup.map_err(io::Error::other);
+        }
+        let lookup: Lookup = tokio::net::lookup_host((name.as_str(), 0))
+            .await
+            .map(|addrs| addrs.collect())
+            .map_err(|err| err.to_string());
+        self.insert(name, &lookup);
+        lookup.map_err(io::Error::other)
+    }
+
+    fn get(&self, name: &str) -> Option<Lookup> {
+        let mut entries = self.lock();
+        let (resolved_at, lookup) = entries.get(name)?;
+        if resolved_at.elapsed() < self.ttl_for(lookup) {
+            return Some(lookup.clone());
+        }
+        entries.remove(name);
+        None
+    }
+
+    fn insert(&self, name: String, lookup: &Lookup) {
+        if self.ttl.is_zero() || self.ttl_for(lookup).is_zero() || self.max_entries == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.lock();
+        if entries.len() >= self.max_entries {
+            entries.retain(|_, (resolved_at, lookup)| {
+                now.duration_since(*resolved_at) < self.ttl_for(lookup)
+            });
+            if entries.len() >= self.max_entries {
+                return;
+            }
+        }
+        entries.insert(name, (now, lookup.clone()));
+    }
+}
@@ -0,0 +1,167 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+// Ошибки обработки запроса. Клиент получает JSON вида
+// {"error":"decode_failed","message":"..."} с подходящим HTTP-кодом.
+#[derive(Debug, Clone)]
+pub enum AppError {
+    // Строка запроса не разбирается в параметры
+    InvalidQuery(String),
+    // Тело запроса не разбирается как JSON нужного вида
+    InvalidBody(String),
+    // Недопустимое значение параметра запроса
+    InvalidParam(&'static str),
+    // Не передан ни URL, ни файл
+    NoImage,
+    // URL не разбирается или схема не http/https
+    InvalidUrl,
+    // data: URI без image/-типа, без base64 или с испорченными данными
+    InvalidDataUri,
+    // Хост не разрешён или указывает во внутреннюю сеть
+    HostNotAllowed,
+    // Источник недоступен или ответил ошибкой
+    FetchFailed,
+    // Хост источника раз за разом ошибался, запросы к нему временно не идут;
+    // через сколько секунд повторить
+    UpstreamUnavailable(u64),
+    // Источник ответил 404
+    SourceNotFound,
+    // Источник не ответил за FETCH_TIMEOUT_SECONDS
+    FetchTimeout,
+    // Исходник больше допустимого размера
+    TooLarge,
+    // Результаты batch вместе больше BATCH_MAX_OUTPUT_BYTES
+    BatchTooLarge,
+    // Ошибка чтения multipart-загрузки
+    UploadFailed,
+    // Байты не декодируются как изображение
+    DecodeFailed(String),
+    // Изображение декодируется, но его цветовое пространство не переводится в RGB
+    UnsupportedColorSpace(String),
+    // Ошибка fast_image_resize
+    ResizeFailed(String),
+    // Ошибка кодирования результата
+    EncodeFailed(String),
+    // Задача в blocking-пуле не завершилась
+    ProcessingFailed,
+    // Обработка не уложилась в PROCESSING_TIMEOUT_SECONDS
+    ProcessingTimeout,
+    // Подпись запроса отсутствует или не совпадает
+    InvalidSignature,
+    // Клиент превысил лимит запросов; через сколько секунд повторить
+    RateLimited(u64),
+    // Все места MAX_CONCURRENCY заняты; через сколько секунд повторить
+    Overloaded(u64),
+}
+
+impl AppError {
+    // Машиночитаемый код для поля `error`
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::InvalidQuery(_) => "invalid_query",
+            AppError::InvalidBody(_) => "invalid_body",
+            AppError::InvalidParam(_) => "invalid_param",
+            AppError::NoImage => "no_image",
+            AppError::InvalidUrl => "invalid_url",
+            AppError::InvalidDataUri => "invalid_data_uri",
+            AppError::HostNotAllowed => "host_not_allowed",
+            AppError::FetchFailed => "fetch_failed",
+            AppError::UpstreamUnavailable(_) => "upstream_unavailable",
+            AppError::SourceNotFound => "source_not_found",
+            AppError::FetchTimeout => "fetch_timeout",
+            AppError::TooLarge => "too_large",
+            AppError::BatchTooLarge => "batch_too_large",
+            AppError::UploadFailed => "upload_failed",
+            AppError::DecodeFailed(_) => "decode_failed",
+            AppError::UnsupportedColorSpace(_) => "unsupported_color_space",
+            AppError::ResizeFailed(_) => "resize_failed",
+            AppError::EncodeFailed(_) => "encode_failed",
+            AppError::ProcessingFailed => "processing_failed",
+            AppError::ProcessingTimeout => "processing_timeout",
+            AppError::InvalidSignature => "invalid_signature",
+            AppError::RateLimited(_) => "rate_limited",
+            AppError::Overloaded(_) => "overloaded",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::InvalidQuery(err) => write!(f, "Invalid query: {err}"),
+            AppError::InvalidBody(err) => write!(f, "Invalid request body: {err}"),
+            AppError::InvalidParam(message) => f.write_str(message),
+            AppError::NoImage => f.write_str("No image provided"),
+            AppError::InvalidUrl => f.write_str("Invalid image URL"),
+            AppError::InvalidDataUri => f.write_str("Malformed data: URI"),
+            AppError::HostNotAllowed => f.write_str("Host is not allowed"),
+            AppError::FetchFailed => f.write_str("Failed to fetch image from URL"),
+            AppError::UpstreamUnavailable(_) => {
+                f.write_str("Image host is failing, requests to it are paused")
+            }
+            AppError::SourceNotFound => f.write_str("Image not found at URL"),
+            AppError::FetchTimeout => f.write_str("Timed out fetching image from URL"),
+            AppError::TooLarge => f.write_str("Image is too large"),
+            AppError::BatchTooLarge => f.write_str("Batch output exceeds the size limit"),
+            AppError::UploadFailed => f.write_str("Error reading file chunk"),
+            AppError::DecodeFailed(err) => write!(f, "Failed to decode image: {err}"),
+            AppError::UnsupportedColorSpace(err) => write!(f, "Unsupported color space: {err}"),
+            AppError::ResizeFailed(err) => write!(f, "Failed to resize image: {err}"),
+            AppError::EncodeFailed(err) => write!(f, "Failed to encode image: {err}"),
+            AppError::ProcessingFailed => f.write_str("Image processing failed"),
+            AppError::ProcessingTimeout => f.write_str("Image processing timed out"),
+            AppError::InvalidSignature => f.write_str("Missing or invalid signature"),
+            AppError::RateLimited(_) => f.write_str("Too many requests"),
+            AppError::Overloaded(_) => f.write_str("Server is busy, try again later"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::InvalidQuery(_)
+            | AppError::InvalidBody(_)
+            | AppError::InvalidParam(_)
+            | AppError::NoImage
+            | AppError::InvalidUrl
+            | AppError::InvalidDataUri
+            | AppError::UploadFailed => StatusCode::BAD_REQUEST,
+            AppError::SourceNotFound => StatusCode::NOT_FOUND,
+            AppError::FetchFailed | AppError::UpstreamUnavailable(_) => StatusCode::BAD_GATEWAY,
+            AppError::FetchTimeout => StatusCode::GATEWAY_TIMEOUT,
+            AppError::HostNotAllowed | AppError::InvalidSignature => StatusCode::FORBIDDEN,
+            AppError::TooLarge | AppError::BatchTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::DecodeFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::UnsupportedColorSpace(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::ProcessingTimeout => StatusCode::REQUEST_TIMEOUT,
+            AppError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::ResizeFailed(_) | AppError::EncodeFailed(_) | AppError::ProcessingFailed => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::RateLimited(seconds)
+        | AppError::Overloaded(seconds)
+        | AppError::UpstreamUnavailable(seconds) = self
+        {
+            response.insert_header((header::RETRY_AFTER, seconds.to_string()));
+        }
+        response.json(ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
+        })
+    }
+}
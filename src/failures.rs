@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::circuit::{CircuitBreaker, CircuitCounts};
+use crate::error::AppError;
+use crate::fetch;
+
+// Сколько URL помним одновременно
+const MAX_ENTRIES: usize = 10_000;
+
+// Недавние неудачные загрузки: пока запись свежая, запрос по тому же URL
+// сразу получает ту же ошибку и не нагружает сломанный источник.
+// Хосты, которые ошибаются раз за разом, отсекает предохранитель, см. circuit.rs.
+pub struct FailedFetches {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, AppError)>>,
+    circuit: CircuitBreaker,
+}
+
+impl FailedFetches {
+    // ttl = 0 выключает запоминание
+    pub fn new(ttl: Duration, circuit: CircuitBreaker) -> Self {
+        FailedFetches {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            circuit,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, (Instant, AppError)>> {
+        self.entries.lock().unwrap_or_else(|poisoned| {
+            tracing::warn!("failed fetch map mutex was poisoned, recovering");
+            self.entries.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    // Ошибка, которую сразу получит запрос по `url`: недавняя ошибка этого URL
+    // или разомкнутая цепь его хоста
+    pub fn get(&self, url: &str) -> Option<AppError> {
+        let normalized = fetch::normalize_url(url);
+        {
+            let mut entries = self.lock();
+            if let Some((failed_at, err)) = entries.get(&normalized) {
+                if failed_at.elapsed() < self.ttl {
+                    return Some(err.clone());
+                }
+                entries.remove(&normalized);
+            }
+        }
+        let host = host(url)?;
+        self.circuit
+            .check(&host)
+            .err()
+            .map(AppError::UpstreamUnavailable)
+    }
+
+    // Исходник загружен: хост жив
+    pub fn record_success(&self, url: &str) {
+        if let Some(host) = host(url) {
+            self.circuit.record_success(&host);
+        }
+    }
+
+    pub fn circuit_counts(&self) -> CircuitCounts {
+        self.circuit.counts()
+    }
+
+    // Запоминаются только ошибки самого источника: 404, 5xx, обрывы и таймауты.
+    // Для предохранителя обрывы, таймауты и ответы с ошибкой - сбой хоста,
+    // а 404 и слишком большой исходник - признак, что хост отвечает.
+    pub fn record(&self, url: &str, err: &AppError) {
+        if let Some(host) = host(url) {
+            match err {
+                AppError::FetchFailed | AppError::FetchTimeout => {
+                    self.circuit.record_failure(&host)
+                }
+                AppError::SourceNotFound | AppError::TooLarge => self.circuit.record_success(&host),
+                _ => {}
+            }
+        }
+        if self.ttl.is_zero()
+            || !matches!(
+                err,
+                AppError::FetchFailed | AppError::FetchTimeout | AppError::SourceNotFound
+            )
+        {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.lock();
+        if entries.len() >= MAX_ENTRIES {
+            let ttl = self.ttl;
+            entries.retain(|_, (failed_at, _)| now.duration_since(*failed_at) < ttl);
+            if entries.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        entries.insert(fetch::normalize_url(url), (now, err.clone()));
+    }
+}
+
+// Хост http(s)-адреса; у data: и file: хоста нет
+fn host(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    url.host_str().map(str::to_ascii_lowercase)
+}
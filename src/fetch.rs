@@ -0,0 +1,530 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use percent_encoding::percent_decode_str;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::{HeaderMap, HeaderValue, LOCATION, REFERER};
+use reqwest::redirect::Policy;
+use reqwest::{Client, Response, Url};
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{Config, UpstreamReferer};
+use crate::dns::DnsCache;
+
+// Сколько редиректов разрешено пройти при загрузке исходника
+const MAX_REDIRECTS: usize = 10;
+
+// Почему URL исходника отклонён
+pub enum UrlRejection {
+    // Не разбирается или схема не http/https
+    Malformed,
+    // Хост не из ALLOWED_HOSTS либо приватный адрес
+    Forbidden,
+}
+
+// Почему не удалось дочитать тело ответа
+pub enum BodyError {
+    // Ответ больше MAX_DOWNLOAD_BYTES
+    TooLarge,
+    // Соединение оборвалось или тело не читается
+    Read,
+    // Не уложились в FETCH_TIMEOUT_SECONDS
+    Timeout,
+}
+
+// Адрес назначения попал во внутреннюю сеть
+#[derive(Debug)]
+pub struct BlockedAddress;
+
+impl fmt::Display for BlockedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("destination address is not public")
+    }
+}
+
+impl Error for BlockedAddress {}
+
+// Проверяет URL до запроса: схему, список разрешённых хостов и IP-литералы
+pub fn check_url(url: &str, allowed_hosts: Option<&[String]>) -> Result<Url, UrlRejection> {
+    let url = Url::parse(url).map_err(|_| UrlRejection::Malformed)?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(UrlRejection::Malformed);
+    }
+    let host = match url.host_str() {
+        Some(host) => host.to_ascii_lowercase(),
+        None => return Err(UrlRejection::Malformed),
+    };
+
+    // IPv6-литералы приходят в квадратных скобках
+    let literal_ip = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>();
+    if literal_ip.is_ok_and(|ip| !is_public_ip(ip)) {
+        return Err(UrlRejection::Forbidden);
+    }
+
+    if let Some(allowed) = allowed_hosts {
+        if !allowed.iter().any(|entry| host_matches(entry, &host)) {
+            return Err(UrlRejection::Forbidden);
+        }
+    }
+
+    Ok(url)
+}
+
+// Изображение, встроенное в URL: `data:image/<тип>[;параметры];base64,<данные>`.
+// Тип проверяется только на префикс image/, формат всё равно определяется по байтам.
+// Возвращает тип и байты; None, если URI не такого вида или base64 не декодируется.
+pub fn decode_data_uri(uri: &str) -> Option<(&str, Vec<u8>)> {
+    let (meta, data) = uri.strip_prefix("data:")?.split_once(',')?;
+    let (media_type, encoding) = meta.rsplit_once(';')?;
+    if !encoding.eq_ignore_ascii_case("base64") {
+        return None;
+    }
+    let mime = media_type.split(';').next().unwrap_or_default();
+    if !mime
+        .get(..6)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("image/"))
+    {
+        return None;
+    }
+    Some((mime, BASE64.decode(data).ok()?))
+}
+
+// URL в виде для ключей объединения запросов и памяти о неудачах, чтобы косметически
+// разные ссылки на одно изображение совпадали: хост в нижнем регистре и порт по
+// умолчанию убирает сам Url, здесь же сортируются параметры запроса, отбрасываются
+// пустые и фрагмент (он не уходит на сервер). Скачивается всегда исходный URL.
+// Всё, что не разбирается как http/https, остаётся как есть.
+pub fn normalize_url(url: &str) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return url.to_string();
+    }
+    parsed.set_fragment(None);
+    let mut pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .into_owned()
+        .filter(|(name, value)| !name.is_empty() || !value.is_empty())
+        .collect();
+    if pairs.is_empty() {
+        parsed.set_query(None);
+    } else {
+        // Сортировка устойчивая: повторы одного параметра сохраняют порядок
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    parsed.into()
+}
+
+// Путь файла в LOCAL_ASSET_DIR по URL `file:///dir/name.jpg`. None - URL не
+// разбирается, указывает на хост, или сегмент пути пытается выйти из каталога.
+// Симлинки здесь не разрешаются, это проверяется после canonicalize.
+pub fn local_asset_path(base: &Path, url: &str) -> Option<PathBuf> {
+    let url = Url::parse(url).ok()?;
+    if url.scheme() != "file" || url.host_str().is_some_and(|host| !host.is_empty()) {
+        return None;
+    }
+    let mut path = base.to_path_buf();
+    for segment in url.path_segments()? {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        if segment == ".." || segment.contains(['/', '\\', '\0']) {
+            return None;
+        }
+        if !segment.is_empty() && segment != "." {
+            path.push(&*segment);
+        }
+    }
+    Some(path)
+}
+
+// Клиент для загрузки исходников: DNS отдаёт только публичные адреса.
+// Редиректы клиент не проходит, это делает get().
+// Один клиент на процесс, чтобы переиспользовать keep-alive соединения и TLS-сессии.
+pub fn build_client(config: &Config) -> Client {
+    let resolver = PublicOnlyResolver {
+        cache: Arc::new(DnsCache::new(
+            Duration::from_secs(config.dns_cache_ttl_seconds),
+            Duration::from_secs(config.dns_cache_negative_ttl_seconds),
+            config.dns_cache_max_entries,
+        )),
+    };
+    Client::builder()
+        .dns_resolver(Arc::new(resolver))
+        .redirect(Policy::none())
+        // Через прокси из HTTP_PROXY/HTTPS_PROXY имя резолвит прокси, а не
+        // PublicOnlyResolver, и проверка адресов обходится
+        .no_proxy()
+        .user_agent(config.upstream_user_agent.clone())
+        .connect_timeout(Duration::from_secs(config.fetch_connect_timeout_seconds))
+        .timeout(Duration::from_secs(config.fetch_timeout_seconds))
+        .build()
+        .expect("failed to build HTTP client")
+}
+
+// Почему не удалось получить ответ источника
+pub enum GetError {
+    Request(reqwest::Error),
+    // Редирект на запрещённый хост или непубличный адрес
+    Blocked,
+    // Больше MAX_REDIRECTS редиректов подряд
+    TooManyRedirects,
+}
+
+impl fmt::Display for GetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GetError::Request(err) => err.fmt(f),
+            GetError::Blocked => f.write_str("redirect to a host that is not allowed"),
+            GetError::TooManyRedirects => f.write_str("too many redirects"),
+        }
+    }
+}
+
+// GET исходника с проходом редиректов. Редиректы проходятся здесь, а не в reqwest:
+// он переносит заголовки запроса на другой хост, убирая только Authorization и Cookie,
+// а X-Api-Key и подобные из UPSTREAM_HEADERS должны уходить только хосту исходного URL.
+// UPSTREAM_HOST_HEADERS выбираются заново для хоста каждого редиректа.
+pub async fn get(client: &Client, config: &Config, url: &Url) -> Result<Response, GetError> {
+    let mut current = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let mut request = client.get(current.clone());
+        if let Some(referer) = referer(config, &current) {
+            request = request.header(REFERER, referer);
+        }
+        if current.origin() == url.origin() {
+            request = request.headers(config.upstream_headers.clone());
+        }
+        if let Some(headers) = host_headers(config, &current) {
+            request = request.headers(headers.clone());
+        }
+        let resp = request.send().await.map_err(GetError::Request)?;
+        let redirect = matches!(resp.status().as_u16(), 301 | 302 | 303 | 307 | 308);
+        let location = resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|location| current.join(location).ok());
+        let (true, Some(location)) = (redirect, location) else {
+            return Ok(resp);
+        };
+        current = check_url(location.as_str(), config.allowed_hosts.as_deref())
+            .map_err(|_| GetError::Blocked)?;
+    }
+    Err(GetError::TooManyRedirects)
+}
+
+// Заголовки из UPSTREAM_HOST_HEADERS для хоста URL. Точное имя хоста главнее
+// шаблона, из шаблонов - самый длинный: так их упорядочил Config::load.
+pub fn host_headers<'a>(config: &'a Config, url: &Url) -> Option<&'a HeaderMap> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    config
+        .upstream_host_headers
+        .iter()
+        .find(|(entry, _)| host_matches(entry, &host))
+        .map(|(_, headers)| headers)
+}
+
+// Referer из UPSTREAM_REFERER для запроса к `url`. Referer из UPSTREAM_HEADERS
+// его заменяет, get() добавляет их позже.
+fn referer(config: &Config, url: &Url) -> Option<HeaderValue> {
+    match config.upstream_referer.as_ref()? {
+        UpstreamReferer::Fixed(referer) => Some(referer.clone()),
+        UpstreamReferer::Origin => {
+            HeaderValue::from_str(&format!("{}/", url.origin().ascii_serialization())).ok()
+        }
+    }
+}
+
+// Читает тело ответа кусками, обрывая загрузку сверх `limit` байт.
+// Content-Length проверяется заранее, но ему не доверяем: его может не быть или он врёт.
+pub async fn read_body(mut resp: Response, limit: usize) -> Result<Vec<u8>, BodyError> {
+    if resp.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(BodyError::TooLarge);
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await.map_err(|err| {
+        if err.is_timeout() {
+            BodyError::Timeout
+        } else {
+            BodyError::Read
+        }
+    })? {
+        if body.len() + chunk.len() > limit {
+            return Err(BodyError::TooLarge);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+// Была ли ошибка запроса вызвана блокировкой внутреннего адреса
+pub fn is_blocked(err: &reqwest::Error) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if err.is::<BlockedAddress>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+// Резолвер, который отказывает, если имя указывает хоть на один внутренний адрес.
+// Соединение идёт к уже проверенным адресам, так что подмена DNS между проверкой
+// и запросом не помогает. Адреса берутся из DnsCache.
+struct PublicOnlyResolver {
+    cache: Arc<DnsCache>,
+}
+
+impl Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let cache = self.cache.clone();
+        Box::pin(async move {
+            let addrs = cache.lookup(name.as_str()).await?;
+            if addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+                return Err(Box::new(BlockedAddress) as Box<dyn Error + Send + Sync>);
+            }
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+// `example.com` совпадает только сам с собой, `*.example.com` - с поддоменами
+fn host_matches(entry: &str, host: &str) -> bool {
+    match entry.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(suffix)
+            .is_some_and(|prefix| prefix.ends_with('.')),
+        None => entry == host,
+    }
+}
+
+// Отсекает loopback, частные, link-local, CGNAT и прочие непубличные диапазоны.
+// В адресах IPv6, несущих внутри IPv4 (mapped, NAT64, 6to4), проверяется этот IPv4:
+// шлюз доставит запрос именно туда.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // CGNAT 100.64.0.0/10
+                || (a == 100 && (b & 0xc0) == 64)
+                // Протокольные назначения IETF 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // Anycast ретрансляторов 6to4 192.88.99.0/24
+                || (a == 192 && b == 88 && c == 99)
+                // Тестирование сетей 198.18.0.0/15
+                || (a == 198 && (b & 0xfe) == 18)
+                // Зарезервированные 240.0.0.0/4
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = embedded_ipv4(ip) {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            let [first, second, third, fourth, ..] = ip.segments();
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                // Устаревшие site-local fec0::/10
+                || (first & 0xffc0) == 0xfec0
+                // Документация 2001:db8::/32
+                || (first == 0x2001 && second == 0xdb8)
+                // Discard-only 100::/64
+                || (first == 0x100 && second == 0 && third == 0 && fourth == 0)
+                // Прочие 64:ff9b::/32, например локальный NAT64 64:ff9b:1::/48:
+                // адрес за ними не извлечь
+                || (first == 0x64 && second == 0xff9b)
+                // Teredo 2001::/32: IPv4 внутри закодирован, проще не пускать
+                || (first == 0x2001 && second == 0))
+        }
+    }
+}
+
+// IPv4 внутри IPv6: ::ffff:a.b.c.d (mapped), ::a.b.c.d (устаревший compatible),
+// 64:ff9b::a.b.c.d (NAT64) и 2002:aabb:ccdd::/48 (6to4)
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let octets = ip.octets();
+    match segments {
+        [0x64, 0xff9b, 0, 0, 0, 0, ..] => Some(Ipv4Addr::new(
+            octets[12], octets[13], octets[14], octets[15],
+        )),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        // ::1 и :: тоже попадают сюда, как 0.0.0.1 и 0.0.0.0, и отсекаются по 0/8
+        _ => ip.to_ipv4(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public_ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_ipv4() {
+        for ip in [
+            "8.8.8.8",
+            "1.1.1.1",
+            "198.20.0.1",
+            "192.0.1.1",
+            "100.128.0.1",
+            "192.88.98.1",
+        ] {
+            assert!(public(ip), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "0.1.2.3",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "192.0.2.1",
+            "192.88.99.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!public(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn public_ipv6() {
+        for ip in [
+            "2606:4700::1111",
+            "64:ff9b::808:808",
+            "2002:808:808::1",
+            "2001:db9::1",
+            "100:0:0:1::1",
+            "2a00:1450:4001::1",
+        ] {
+            assert!(public(ip), "{ip}");
+        }
+        for ip in [
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "::127.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a00:1",
+            "64:ff9b:1::808:808",
+            "2002:7f00:1::1",
+            "2002:a00:1::",
+            "2001::1",
+            "fec0::1",
+            "feff::1",
+            "2001:db8::1",
+            "2001:db8:ffff::1",
+            "100::1",
+            "100::ffff:ffff:ffff:ffff",
+            "2002:c058:6301::1",
+        ] {
+            assert!(!public(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn check_url_rejects_private_and_malformed() {
+        for url in [
+            "http://127.0.0.1/a.png",
+            "http://[::1]/a.png",
+            "http://[::ffff:169.254.169.254]/",
+            "http://[64:ff9b::7f00:1]/",
+            "http://10.0.0.1:8080/",
+        ] {
+            assert!(
+                matches!(check_url(url, None), Err(UrlRejection::Forbidden)),
+                "{url}"
+            );
+        }
+        for url in ["ftp://example.com/a.png", "file:///etc/passwd", "not a url"] {
+            assert!(
+                matches!(check_url(url, None), Err(UrlRejection::Malformed)),
+                "{url}"
+            );
+        }
+        assert!(check_url("https://example.com/a.png", None).is_ok());
+    }
+
+    #[test]
+    fn check_url_applies_allowed_hosts() {
+        let allowed = ["*.example.com".to_string(), "cdn.test".to_string()];
+        for url in ["https://img.example.com/a.png", "http://CDN.test/a.png"] {
+            assert!(check_url(url, Some(&allowed)).is_ok(), "{url}");
+        }
+        for url in [
+            "https://example.com/a.png",
+            "https://badexample.com/a.png",
+            "https://cdn.test.evil.com/a.png",
+        ] {
+            assert!(
+                matches!(check_url(url, Some(&allowed)), Err(UrlRejection::Forbidden)),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn host_matching() {
+        assert!(host_matches("example.com", "example.com"));
+        assert!(!host_matches("example.com", "img.example.com"));
+        assert!(host_matches("*.example.com", "img.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn normalized_urls() {
+        assert_eq!(
+            normalize_url("HTTP://Example.COM:80/p?b=2&a=1&&#frag"),
+            "http://example.com/p?a=1&b=2"
+        );
+        assert_eq!(
+            normalize_url("https://example.com/p?a=2&a=1"),
+            "https://example.com/p?a=2&a=1"
+        );
+        assert_eq!(
+            normalize_url("https://example.com/p?"),
+            "https://example.com/p"
+        );
+        assert_eq!(
+            normalize_url("data:image/png;base64,AA=="),
+            "data:image/png;base64,AA=="
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
+
+// Состояние процесса для проверок живости и готовности
+pub struct Health {
+    started: Instant,
+    ready: AtomicBool,
+    // Запросы, которые сейчас обрабатываются
+    inflight: AtomicUsize,
+    // Запросы, брошенные без ответа (например, по истечении времени на остановку)
+    dropped: AtomicUsize,
+}
+
+// Учитывает запрос в `inflight`, пока жив. Если его уничтожили без `finish`,
+// запрос считается брошенным.
+pub struct InflightGuard {
+    health: web::Data<Health>,
+    finished: bool,
+}
+
+impl InflightGuard {
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.health.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.health.inflight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            started: Instant::now(),
+            ready: AtomicBool::new(false),
+            inflight: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn track(health: &web::Data<Health>) -> InflightGuard {
+        health.inflight.fetch_add(1, Ordering::Relaxed);
+        InflightGuard {
+            health: health.clone(),
+            finished: false,
+        }
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.inflight.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Готов ли сервер принимать запросы: после привязки к адресу и до сигнала остановки
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize)]
+struct HealthBody {
+    status: &'static str,
+    uptime_seconds: u64,
+}
+
+// Живость: процесс отвечает, изображения не обрабатываются
+pub async fn health(health: web::Data<Health>) -> HttpResponse {
+    HttpResponse::Ok().json(HealthBody {
+        status: "ok",
+        uptime_seconds: health.started.elapsed().as_secs(),
+    })
+}
+
+// Готовность: 503, пока сервер не привязан к адресу и после сигнала остановки
+pub async fn ready(health: web::Data<Health>) -> HttpResponse {
+    if health.ready.load(Ordering::Relaxed) {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::ServiceUnavailable().finish()
+    }
+}
@@ -0,0 +1,68 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use std::io::IsTerminal;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+use crate::access_log;
+use crate::config::Config;
+
+// Уровень по умолчанию, если RUST_LOG не задан или не разбирается
+const DEFAULT_FILTER: &str = "info";
+
+// Логи в stdout, уровень и фильтры по модулям из RUST_LOG
+pub fn init() {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        // Цвета только в терминале, в файлах и сборщиках логов они мешают
+        .with_ansi(std::io::stdout().is_terminal())
+        .init();
+}
+
+// Span на каждый запрос и итоговая запись со статусом и временем.
+// Формат ответа, объединение с идущим запросом и время обработки
+// дописывают в span обработчики.
+pub async fn trace(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let client = req
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    let redact = req
+        .app_data::<web::Data<Config>>()
+        .is_some_and(|config| config.log_redact_urls);
+    let span = tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = access_log::redact_path(req.path(), redact),
+        client,
+        format = Empty,
+        coalesced = Empty,
+        processing_ms = Empty,
+    );
+
+    async move {
+        let started = Instant::now();
+        let response = next.call(req).await;
+        let status = match &response {
+            Ok(response) => response.status(),
+            Err(err) => err.as_response_error().status_code(),
+        };
+        tracing::info!(
+            status = status.as_u16(),
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "request completed"
+        );
+        response
+    }
+    .instrument(span)
+    .await
+}
@@ -1,128 +1,2607 @@
+mod access_log;
+mod analyze;
+mod batch;
+mod blurhash;
+mod buffers;
+mod circuit;
+mod coalesce;
+mod color;
+mod compare;
+mod compress;
+mod concurrency;
+mod config;
+mod cors;
+mod dns;
+mod error;
+mod failures;
+mod fetch;
+mod health;
+mod logging;
+mod memory;
+mod metadata;
+mod monitoring;
+mod path_params;
+mod pool;
+mod preset;
+mod quality;
+mod ratelimit;
+mod selftest;
+mod signing;
+mod smartcrop;
+mod srcset;
+mod watermark;
+
+use access_log::CacheStatus;
 use actix_multipart::Multipart;
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{
+    self, Accept, CacheControl, CacheDirective, ContentRange, ContentRangeSpec, EntityTag,
+    HttpDate, IfModifiedSince, IfNoneMatch, IfRange, LastModified, Quality, Range,
+};
+use actix_web::middleware::{from_fn, Compress};
+use actix_web::rt::time;
+use actix_web::{web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer};
+use circuit::CircuitBreaker;
+use coalesce::Inflight;
+use concurrency::ConcurrencyLimit;
+use config::{Config, QualityDefaults};
+use error::AppError;
+use failures::FailedFetches;
 use fast_image_resize as fir;
 use fast_image_resize::images::Image;
-use fast_image_resize::Resizer;
+use fast_image_resize::{FilterType, ResizeAlg, ResizeOptions, Resizer};
+use fetch::{BodyError, GetError, UrlRejection};
 use futures_util::StreamExt;
-use image::{io::Reader as ImageReader, DynamicImage, ImageBuffer, ImageOutputFormat, Rgba};
+use health::Health;
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::{WebPDecoder, WebPEncoder, WebPQuality};
+use image::{
+    imageops, io::Reader as ImageReader, AnimationDecoder, DynamicImage, ImageBuffer,
+    ImageOutputFormat, Rgb, Rgba, RgbaImage,
+};
+use memory::MemoryBudget;
+use metrics::{counter, histogram};
+use pool::ProcessingPool;
+use preset::Presets;
+use quality::QualityCurve;
+use ratelimit::RateLimiter;
+use ravif::Img;
+use rgb::FromSlice;
 use serde::Deserialize;
+use sha1::{Digest, Sha1};
 use std::io::Cursor;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use watermark::{Position, Watermarks};
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 struct ResizeParams {
-    width: u32,
-    height: u32,
-    #[serde(default = "default_quality")]
-    quality: u8,
+    // Хотя бы одна сторона обязательна: напрямую или через `size`.
+    // Без второй она считается по пропорциям исходника.
+    width: Option<u32>,
+    height: Option<u32>,
+    // Сокращение для width/height: `300`, `300x200` или `x200`
+    size: Option<String>,
+    // Вместо width/height: обе стороны исходника (после поворота и crop), умноженные
+    // на коэффициент от 0.01 до 4; с width или height не действует
+    scale: Option<f32>,
+    // Без quality в запросе и в пресете - DEFAULT_QUALITY_<ФОРМАТ>; можно дробное.
+    // auto - подбор по детализации изображения, см. quality::auto_quality
+    quality: Option<String>,
+    // Как понимать quality: linear - шкала кодировщика (по умолчанию), perceptual -
+    // шкала JPEG, переводится в шкалу формата вывода, см. quality.rs
+    quality_curve: Option<String>,
     url: Option<String>,
+    // Что отдать, если `url` не скачался или не декодируется; ответ с X-Fallback: true
+    fallback_url: Option<String>,
+    // Именованный набор quality, format и filter, см. preset.rs
+    preset: Option<String>,
+    format: Option<String>,
+    // Если явный `format` не входит в Accept клиента, отдать лучший формат,
+    // который клиент принимает, вместо нечитаемого для него изображения
+    strict_accept: Option<bool>,
+    background: Option<String>,
+    filter: Option<String>,
+    fit: Option<String>,
+    // true - разрешить результат крупнее исходника. По умолчанию размер
+    // уменьшается с сохранением пропорций запроса, пока не уложится в исходник:
+    // из пикселя 1x1 или иконки 16x16 не получится размытое пятно 800x600
+    allow_upscale: Option<bool>,
+    // false - сохранить ICC-профиль исходника, см. metadata.rs
+    strip: Option<bool>,
+    // true - перевести пиксели из профиля исходника в sRGB и не сохранять профиль,
+    // см. color.rs; false - сохранить профиль, как strip=false
+    color_convert: Option<bool>,
+    // Поворот по часовой стрелке (90, 180, 270) и отражение (horizontal, vertical)
+    // до ресайза: width/height относятся к итоговой ориентации
+    rotate: Option<u16>,
+    flip: Option<String>,
+    // Вырезать область `x,y,w,h` в координатах после поворота, до ресайза.
+    // `smart` вместо области - для fit=cover выбрать самую детальную часть
+    // вместо центра, см. smartcrop.rs; с другими fit не действует.
+    crop: Option<String>,
+    // Для fit=cover: точка `x,y` в долях ширины и высоты (после поворота и crop),
+    // которую нужно держать в кадре, например `0.5,0.3`; важнее crop=smart
+    focal: Option<String>,
+    // Водяной знак из WATERMARK_DIR поверх результата, его положение
+    // (top-left, center, bottom-right и т.п.) и непрозрачность от 0 до 1
+    watermark: Option<String>,
+    watermark_pos: Option<String>,
+    watermark_opacity: Option<f32>,
+    // Сигма размытия по Гауссу и резкости (unsharp mask), в пикселях результата
+    blur: Option<f32>,
+    sharpen: Option<f32>,
+    // Перевести результат в оттенки серого
+    grayscale: Option<bool>,
+    // Дуотон `000044-ffccaa`: яркость каждого пикселя переводится в градиент
+    // от цвета теней к цвету светов, прозрачность сохраняется
+    duotone: Option<String>,
+    // Прогрессивный JPEG: сначала грубое превью, затем уточнения. Обычно на
+    // несколько процентов меньше базового, но кодируется и декодируется дольше.
+    progressive: Option<bool>,
+    // Прореживание цветности JPEG: 444, 422 или 420, по умолчанию 420. 444
+    // убирает цветные ореолы вокруг текста на скриншотах ценой размера.
+    subsampling: Option<String>,
+    // WebP без потерь: лучше для скриншотов и схем, для фото обычно крупнее
+    // lossy. quality при этом не действует. Для других форматов игнорируется.
+    lossless: Option<bool>,
+    // Дожать PNG через oxipng без потерь; заметно дольше обычного кодирования
+    lossless_optimize: Option<bool>,
+    // Предел размера результата: quality понижается, пока вывод не уложится
+    max_bytes: Option<usize>,
+    // max-age ответа в секундах вместо CACHE_MAX_AGE_SECONDS, не больше
+    // CACHE_TTL_MAX_SECONDS; 0 - no-cache
+    cache_ttl: Option<u64>,
+    // Если перекодированный результат больше исходника, а изображение то же самое,
+    // отдаётся исходник с X-Optimized: false; true - всегда перекодировать
+    force_reencode: Option<bool>,
+    // Снижение quality для Save-Data: on, выставляется в prepare, не из запроса
+    #[serde(skip)]
+    quality_reduction: u8,
+    // Форматы для format=best, выставляются в prepare по Accept
+    #[serde(skip)]
+    format_candidates: Vec<image::ImageFormat>,
 }
 
-fn default_quality() -> u8 {
-    80
+// Как вписывать изображение в заданные width x height, по аналогии с CSS object-fit
+#[derive(Clone, Copy, PartialEq)]
+enum Fit {
+    // Растянуть ровно в размер, пропорции не сохраняются
+    Fill,
+    // Уменьшить до размера внутри рамки с сохранением пропорций, без полей
+    Contain,
+    // Заполнить рамку целиком, выступающее обрезается по центру
+    Cover,
 }
 
-async fn resize_image(
-    query: web::Query<ResizeParams>,
-    mut payload: Option<Multipart>,
-) -> impl Responder {
-    let mut img_data: Vec<u8> = Vec::new();
-
-    // Если передан URL
-    if let Some(url) = &query.url {
-        match reqwest::get(url).await {
-            Ok(resp) => match resp.bytes().await {
-                Ok(bytes) => img_data.extend_from_slice(&bytes),
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Failed to read image bytes from URL")
-                }
-            },
-            Err(_) => return HttpResponse::BadRequest().body("Failed to fetch image from URL"),
+// Скорость ravif: 1 - медленно и компактно, 10 - быстро
+const AVIF_SPEED: u8 = 6;
+
+// Верхняя граница сигмы для blur и sharpen: стоимость фильтра растёт вместе с ней
+const MAX_FILTER_SIGMA: f32 = 20.0;
+// Минимальная разница яркости, которую усиливает sharpen
+const SHARPEN_THRESHOLD: i32 = 0;
+
+// Уровень oxipng: 2 - его значение по умолчанию, разумный баланс времени и размера
+const OXIPNG_PRESET: u8 = 2;
+
+// Значение crop для выбора области cover по детализации
+const SMART_CROP: &str = "smart";
+// Значение format: закодировать в несколько форматов и отдать самый маленький
+const BEST_FORMAT: &str = "best";
+
+// Сколько первых байт исходника просматривать в поисках тега <svg>
+const SVG_SNIFF_BYTES: usize = 1024;
+
+// Допустимый диапазон параметра scale
+const MIN_SCALE: f32 = 0.01;
+const MAX_SCALE: f32 = 4.0;
+
+// Ниже этого качества подбор под max_bytes не опускается
+const MIN_AUTO_QUALITY: f32 = 30.0;
+// Ниже этого качества не опускает снижение для Save-Data
+const SAVE_DATA_MIN_QUALITY: f32 = 30.0;
+
+// Формат вывода из параметра `format`; None для неизвестных значений
+fn parse_output_format(value: &str) -> Option<image::ImageFormat> {
+    match value.to_ascii_lowercase().as_str() {
+        "webp" => Some(image::ImageFormat::WebP),
+        "jpeg" | "jpg" => Some(image::ImageFormat::Jpeg),
+        "png" => Some(image::ImageFormat::Png),
+        "avif" => Some(image::ImageFormat::Avif),
+        _ => None,
+    }
+}
+
+// Алгоритм ресайза из параметра `filter`; None для неизвестных значений.
+// Без параметра используется Lanczos3 - значение по умолчанию fast_image_resize.
+fn parse_filter(value: &str) -> Option<ResizeAlg> {
+    match value.to_ascii_lowercase().as_str() {
+        "nearest" => Some(ResizeAlg::Nearest),
+        "triangle" => Some(ResizeAlg::Convolution(FilterType::Bilinear)),
+        "catmull-rom" => Some(ResizeAlg::Convolution(FilterType::CatmullRom)),
+        "gaussian" => Some(ResizeAlg::Convolution(FilterType::Gaussian)),
+        "lanczos3" => Some(ResizeAlg::Convolution(FilterType::Lanczos3)),
+        _ => None,
+    }
+}
+
+fn parse_fit(value: &str) -> Option<Fit> {
+    match value.to_ascii_lowercase().as_str() {
+        "fill" => Some(Fit::Fill),
+        "contain" => Some(Fit::Contain),
+        "cover" => Some(Fit::Cover),
+        _ => None,
+    }
+}
+
+// Наибольший размер с пропорциями исходника, который помещается в рамку
+fn contain_size(src_width: u32, src_height: u32, box_width: u32, box_height: u32) -> (u32, u32) {
+    let scale = f64::min(
+        f64::from(box_width) / f64::from(src_width),
+        f64::from(box_height) / f64::from(src_height),
+    );
+    let fitted =
+        |side: u32, limit: u32| ((f64::from(side) * scale).round() as u32).clamp(1, limit.max(1));
+    (fitted(src_width, box_width), fitted(src_height, box_height))
+}
+
+// Область исходника с пропорциями цели по центру: left, top, width, height
+fn cover_crop(
+    src_width: u32,
+    src_height: u32,
+    dst_width: u32,
+    dst_height: u32,
+) -> (f64, f64, f64, f64) {
+    let (width, height) = (f64::from(src_width), f64::from(src_height));
+    let target = f64::from(dst_width.max(1)) / f64::from(dst_height.max(1));
+    if width / height > target {
+        let cropped = height * target;
+        ((width - cropped) / 2.0, 0.0, cropped, height)
+    } else {
+        let cropped = width / target;
+        (0.0, (height - cropped) / 2.0, width, cropped)
+    }
+}
+
+// Сдвигает область cover_crop так, чтобы точка фокуса была как можно ближе
+// к её центру, но область не выходила за края исходника. Возвращает left и top.
+fn focal_window(
+    src_width: u32,
+    src_height: u32,
+    (focal_x, focal_y): (f64, f64),
+    (width, height): (f64, f64),
+) -> (f64, f64) {
+    let (src_width, src_height) = (f64::from(src_width), f64::from(src_height));
+    (
+        (focal_x * src_width - width / 2.0).clamp(0.0, (src_width - width).max(0.0)),
+        (focal_y * src_height - height / 2.0).clamp(0.0, (src_height - height).max(0.0)),
+    )
+}
+
+// Приводит пиксели к нормальной ориентации, включая зеркальные варианты
+fn apply_orientation(img: RgbaImage, orientation: u32) -> RgbaImage {
+    match orientation {
+        2 => imageops::flip_horizontal(&img),
+        3 => imageops::rotate180(&img),
+        4 => imageops::flip_vertical(&img),
+        5 => imageops::flip_horizontal(&imageops::rotate90(&img)),
+        6 => imageops::rotate90(&img),
+        7 => imageops::flip_horizontal(&imageops::rotate270(&img)),
+        8 => imageops::rotate270(&img),
+        _ => img,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+fn parse_flip(value: &str) -> Option<Flip> {
+    match value.to_ascii_lowercase().as_str() {
+        "horizontal" => Some(Flip::Horizontal),
+        "vertical" => Some(Flip::Vertical),
+        _ => None,
+    }
+}
+
+// Поворот и отражение по запросу клиента, поверх ориентации из EXIF.
+// Сначала поворот, затем отражение.
+fn apply_transform(img: RgbaImage, rotate: Option<u16>, flip: Option<Flip>) -> RgbaImage {
+    let img = match rotate {
+        Some(90) => imageops::rotate90(&img),
+        Some(180) => imageops::rotate180(&img),
+        Some(270) => imageops::rotate270(&img),
+        _ => img,
+    };
+    match flip {
+        Some(Flip::Horizontal) => imageops::flip_horizontal(&img),
+        Some(Flip::Vertical) => imageops::flip_vertical(&img),
+        None => img,
+    }
+}
+
+// Точка фокуса `x,y`, обе координаты от 0 до 1
+fn parse_focal(value: &str) -> Option<(f64, f64)> {
+    let (x, y) = value.split_once(',')?;
+    let (x, y) = (x.trim().parse::<f64>().ok()?, y.trim().parse::<f64>().ok()?);
+    ((0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y)).then_some((x, y))
+}
+
+// Прямоугольник `x,y,w,h` с ненулевыми шириной и высотой
+fn parse_crop(value: &str) -> Option<(u32, u32, u32, u32)> {
+    let mut parts = value.split(',').map(|part| part.trim().parse::<u32>().ok());
+    let rect = (
+        parts.next()??,
+        parts.next()??,
+        parts.next()??,
+        parts.next()??,
+    );
+    if parts.next().is_some() || rect.2 == 0 || rect.3 == 0 {
+        return None;
+    }
+    Some(rect)
+}
+
+// Размер вида `300` (только ширина), `300x200` или `x200` (только высота)
+fn parse_size(value: &str) -> Option<(Option<u32>, Option<u32>)> {
+    let side = |part: &str| -> Option<Option<u32>> {
+        if part.is_empty() {
+            Some(None)
+        } else {
+            part.parse::<u32>().ok().map(Some)
         }
+    };
+    let (width, height) = match value.split_once(['x', 'X']) {
+        Some((width, height)) => (side(width)?, side(height)?),
+        None => (Some(value.parse::<u32>().ok()?), None),
+    };
+    (width.is_some() || height.is_some()).then_some((width, height))
+}
+
+// Прореживание цветности JPEG
+fn parse_subsampling(value: &str) -> Option<jpeg_encoder::SamplingFactor> {
+    match value {
+        "444" | "4:4:4" => Some(jpeg_encoder::SamplingFactor::R_4_4_4),
+        "422" | "4:2:2" => Some(jpeg_encoder::SamplingFactor::R_4_2_2),
+        "420" | "4:2:0" => Some(jpeg_encoder::SamplingFactor::R_4_2_0),
+        _ => None,
     }
-    // Иначе ожидаем multipart загрузку
-    else if let Some(payload) = payload.as_mut() {
-        while let Some(item) = payload.next().await {
-            let mut field = match item {
-                Ok(f) => f,
-                Err(_) => continue,
-            };
+}
 
-            while let Some(chunk) = field.next().await {
-                match chunk {
-                    Ok(bytes) => img_data.extend_from_slice(&bytes),
-                    Err(_) => return HttpResponse::BadRequest().body("Error reading file chunk"),
-                }
-            }
+// Цвет фона в виде `ffffff` или `#ffffff`
+fn parse_hex_color(value: &str) -> Option<Rgb<u8>> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+// Пара цветов дуотона `тени-света`, каждый как у background
+fn parse_duotone(value: &str) -> Option<(Rgb<u8>, Rgb<u8>)> {
+    let (shadows, highlights) = value.split_once('-')?;
+    Some((parse_hex_color(shadows)?, parse_hex_color(highlights)?))
+}
+
+// Переводит яркость пикселей в градиент между двумя цветами, альфа не меняется
+fn apply_duotone(img: &mut RgbaImage, (shadows, highlights): (Rgb<u8>, Rgb<u8>)) {
+    // Цвет для каждого из 256 значений яркости
+    let gradient: Vec<[u8; 3]> = (0..=255u32)
+        .map(|luma| {
+            [0, 1, 2].map(|c| {
+                let (from, to) = (u32::from(shadows[c]), u32::from(highlights[c]));
+                ((from * (255 - luma) + to * luma + 127) / 255) as u8
+            })
+        })
+        .collect();
+    for px in img.pixels_mut() {
+        let [r, g, b, _] = px.0.map(u32::from);
+        let luma = (r * 299 + g * 587 + b * 114) / 1000;
+        let [r, g, b] = gradient[luma as usize];
+        (px[0], px[1], px[2]) = (r, g, b);
+    }
+}
+
+// Накладывает изображение на сплошной фон, смешивая полупрозрачные пиксели по альфе
+fn flatten_onto(img: &mut RgbaImage, background: Rgb<u8>) {
+    for px in img.pixels_mut() {
+        let alpha = u16::from(px[3]);
+        for c in 0..3 {
+            let blended = u16::from(px[c]) * alpha + u16::from(background[c]) * (255 - alpha);
+            px[c] = ((blended + 127) / 255) as u8;
         }
-    } else {
-        return HttpResponse::BadRequest().body("No image provided");
+        px[3] = u8::MAX;
     }
+}
 
-    // Загружаем изображение
-    let img_reader = ImageReader::new(Cursor::new(&img_data))
+// Формат вывода по заголовку Accept: первый поддерживаемый тип в порядке предпочтения.
+// Маски вроде `image/*` не выбирают формат, тогда остаётся формат исходника.
+// AVIF выбирается только явным `format=avif` из-за высокой стоимости кодирования.
+fn negotiate_format(accept: &Accept) -> Option<image::ImageFormat> {
+    let acceptable = Accept(
+        accept
+            .iter()
+            .filter(|item| item.quality > Quality::ZERO)
+            .cloned()
+            .collect(),
+    );
+    acceptable
+        .ranked()
+        .iter()
+        .find_map(|mime| match mime.essence_str() {
+            "image/webp" => Some(image::ImageFormat::WebP),
+            "image/jpeg" => Some(image::ImageFormat::Jpeg),
+            "image/png" => Some(image::ImageFormat::Png),
+            _ => None,
+        })
+}
+
+// Для Save-Data: самый компактный формат из явно перечисленных в Accept, без
+// оглядки на предпочтения клиента; если нет ни WebP, ни JPEG - как negotiate_format
+fn efficient_format(accept: &Accept) -> Option<image::ImageFormat> {
+    [image::ImageFormat::WebP, image::ImageFormat::Jpeg]
+        .into_iter()
+        .find(|format| {
+            accept.iter().any(|item| {
+                item.quality > Quality::ZERO && item.item.essence_str() == format.to_mime_type()
+            })
+        })
+        .or_else(|| negotiate_format(accept))
+}
+
+// Кандидаты для format=best: JPEG всегда (с прозрачностью его заменит PNG),
+// WebP и AVIF - если клиент перечислил их в Accept или Accept нет вовсе.
+// При равном размере побеждает первый, то есть более совместимый.
+fn best_candidates(accept: Option<&Accept>) -> Vec<image::ImageFormat> {
+    let listed = |format: image::ImageFormat| {
+        accept.is_none_or(|accept| {
+            accept.iter().any(|item| {
+                item.quality > Quality::ZERO && item.item.essence_str() == format.to_mime_type()
+            })
+        })
+    };
+    [
+        image::ImageFormat::Jpeg,
+        image::ImageFormat::WebP,
+        image::ImageFormat::Avif,
+    ]
+    .into_iter()
+    .filter(|format| *format == image::ImageFormat::Jpeg || listed(*format))
+    .collect()
+}
+
+// Клиент просит экономить трафик (Save-Data: on) и SAVE_DATA_QUALITY_REDUCTION не 0
+fn save_data(req: &HttpRequest, config: &Config) -> bool {
+    config.save_data_quality_reduction > 0
+        && req
+            .headers()
+            .get("Save-Data")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+}
+
+// Принимает ли клиент формат: тип указан явно или маской `image/*`, `*/*`
+fn accepts_format(accept: &Accept, format: image::ImageFormat) -> bool {
+    let mime = format.to_mime_type();
+    accept
+        .iter()
+        .filter(|item| item.quality > Quality::ZERO)
+        .any(|item| {
+            matches!(item.item.essence_str(), "*/*" | "image/*") || item.item.essence_str() == mime
+        })
+}
+
+// Анимированный ли исходник: GIF больше чем из одного кадра, анимированный WebP или APNG.
+// Кодировщики здесь пишут только статичные изображения, поэтому такие исходники
+// отдаются как есть, а не сводятся к первому кадру.
+fn is_animated(img_data: &[u8], format: Option<image::ImageFormat>) -> bool {
+    let cursor = Cursor::new(img_data);
+    match format {
+        Some(image::ImageFormat::Gif) => GifDecoder::new(cursor)
+            .map(|decoder| decoder.into_frames().take(2).count() > 1)
+            .unwrap_or(false),
+        Some(image::ImageFormat::WebP) => WebPDecoder::new(cursor)
+            .map(|decoder| decoder.has_animation())
+            .unwrap_or(false),
+        Some(image::ImageFormat::Png) => PngDecoder::new(cursor)
+            .map(|decoder| decoder.is_apng())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+// Формат определяется по сигнатуре байтов. Если сигнатура не распознана
+// (у TGA её нет вовсе), используется формат из Content-Type источника.
+fn open_reader(
+    img_data: &[u8],
+    format_hint: Option<image::ImageFormat>,
+) -> Result<ImageReader<Cursor<&[u8]>>, AppError> {
+    let mut reader = ImageReader::new(Cursor::new(img_data))
         .with_guessed_format()
-        .unwrap();
-    let format = img_reader.format().unwrap_or(image::ImageFormat::Png);
-    let img = img_reader.decode().unwrap().to_rgba8();
+        .map_err(|err| AppError::DecodeFailed(err.to_string()))?;
+    if reader.format().is_none() {
+        if let Some(format) = format_hint {
+            reader.set_format(format);
+        }
+    }
+    // Растеризатора SVG в сборке нет: понятная ошибка вместо "format could not be determined"
+    if reader.format().is_none() && is_svg(img_data) {
+        return Err(AppError::DecodeFailed(
+            "SVG sources are not supported".to_string(),
+        ));
+    }
+    Ok(reader)
+}
 
-    let (width_orig, height_orig) = img.dimensions();
+// Декодирует исходник в RGBA8; CMYK и YCCK JPEG - через decode_cmyk_jpeg.
+// Второе значение - был ли исходник в CMYK.
+fn decode_rgba(
+    reader: ImageReader<Cursor<&[u8]>>,
+    img_data: &[u8],
+) -> Result<(RgbaImage, bool), AppError> {
+    if reader.format() == Some(image::ImageFormat::Jpeg) {
+        if let Some(img) = decode_cmyk_jpeg(img_data)? {
+            return Ok((img, true));
+        }
+    }
+    let img = reader
+        .decode()
+        .map_err(|err| AppError::DecodeFailed(err.to_string()))?
+        .to_rgba8();
+    Ok((img, false))
+}
 
-    // Создаем Image для fast_image_resize
-    let mut src_image = Image::new(width_orig, height_orig, fir::PixelType::U8x4);
-    src_image.buffer_mut().copy_from_slice(&img.into_raw());
+// CMYK и YCCK JPEG из допечатной подготовки, в RGBA8; None - у JPEG не 4 канала.
+// image считает каналы записанными инвертированными, как делает Photoshop вместе
+// с маркером Adobe, и без маркера выдаёт неверные цвета. Поэтому такие файлы
+// декодируются здесь, и инверсия выбирается по маркеру. Перевод в RGB наивный:
+// встроенный CMYK-профиль не применяется.
+fn decode_cmyk_jpeg(img_data: &[u8]) -> Result<Option<RgbaImage>, AppError> {
+    let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(img_data));
+    decoder
+        .read_info()
+        .map_err(|err| AppError::DecodeFailed(err.to_string()))?;
+    let Some(info) = decoder.info() else {
+        return Ok(None);
+    };
+    if info.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return Ok(None);
+    }
+    // 0 - CMYK, 2 - YCCK. 1 (YCbCr) у четырёх каналов не бывает, прочие значения
+    // не определены
+    let adobe_transform = metadata::jpeg_adobe_transform(img_data);
+    if let Some(transform @ (1 | 3..)) = adobe_transform {
+        return Err(AppError::UnsupportedColorSpace(format!(
+            "4-channel JPEG with Adobe color transform {transform}"
+        )));
+    }
+    let cmyk = decoder.decode().map_err(|err| match err {
+        jpeg_decoder::Error::Unsupported(feature) => {
+            AppError::UnsupportedColorSpace(format!("{feature:?}"))
+        }
+        err => AppError::DecodeFailed(err.to_string()),
+    })?;
+    // jpeg-decoder отдаёт каналы инвертированными. С маркером Adobe данные
+    // записаны инвертированными, и получается количество краски; без маркера
+    // количество краски - дополнение до 255.
+    let adobe = adobe_transform.is_some();
+    let mut rgba = Vec::with_capacity(cmyk.len());
+    for px in cmyk.chunks_exact(4) {
+        let [c, m, y, k] =
+            [px[0], px[1], px[2], px[3]].map(|value| if adobe { value } else { u8::MAX - value });
+        let paper = |ink: u8| {
+            let light = u16::from(u8::MAX - ink) * u16::from(u8::MAX - k);
+            (light / u16::from(u8::MAX)) as u8
+        };
+        rgba.extend_from_slice(&[paper(c), paper(m), paper(y), u8::MAX]);
+    }
+    let img = RgbaImage::from_raw(u32::from(info.width), u32::from(info.height), rgba)
+        .ok_or_else(|| AppError::DecodeFailed("CMYK pixel buffer size mismatch".to_string()))?;
+    Ok(Some(img))
+}
+
+// Похоже ли начало файла на SVG: тег `<svg` в первом килобайте, после
+// XML-пролога, комментариев или DOCTYPE
+fn is_svg(img_data: &[u8]) -> bool {
+    let head = &img_data[..img_data.len().min(SVG_SNIFF_BYTES)];
+    head.windows(4)
+        .any(|window| window.eq_ignore_ascii_case(b"<svg"))
+}
+
+// Декодирует исходник, меняет размер и кодирует в итоговый формат.
+// Выполняется в blocking-пуле: кодирование AVIF изображения ~1920px занимает
+// больше секунды, JPEG/PNG/WebP обычно укладываются в десятки миллисекунд.
+// Пределы и умолчания передаются по отдельности, без Config: так функцию вызывает selftest
+#[allow(clippy::too_many_arguments)]
+fn process_image(
+    img_data: Vec<u8>,
+    params: &ResizeParams,
+    requested_format: Option<image::ImageFormat>,
+    watermarks: &Watermarks,
+    max_input_pixels: u64,
+    max_output_dimension: u32,
+    format_hint: Option<image::ImageFormat>,
+    default_quality: QualityDefaults,
+    memory: &MemoryBudget,
+) -> Result<Output, AppError> {
+    let started = Instant::now();
+    // Загружаем изображение
+    let img_reader = open_reader(&img_data, format_hint)?;
+    let input_format = img_reader.format();
+    // Размер из заголовка, до декодирования: маленький файл может распаковаться
+    // в гигабайты пикселей, и отменить задачу в blocking-пуле уже не получится
+    let size = open_reader(&img_data, format_hint)?
+        .into_dimensions()
+        .map_err(|err| AppError::DecodeFailed(err.to_string()))?;
+    if u64::from(size.0) * u64::from(size.1) > max_input_pixels {
+        return Err(AppError::TooLarge);
+    }
+    if is_animated(&img_data, input_format) {
+        let content_type = match input_format {
+            Some(image::ImageFormat::Gif) => "image/gif",
+            Some(image::ImageFormat::WebP) => "image/webp",
+            _ => "image/png",
+        };
+        return Ok(Output {
+            bytes: img_data.into(),
+            content_type,
+            quality: None,
+            complexity: None,
+            bytes_saved: None,
+            original_size: size,
+            output_size: size,
+            last_modified: None,
+            passthrough: true,
+            color_unconverted: false,
+            fallback: false,
+            original: false,
+            timings: Timings::default(),
+        });
+    }
+    // Память держится до конца обработки: после ресайза исходник ещё жив
+    let _reservation = memory.reserve(size)?;
+    let mut format = requested_format
+        .or(input_format)
+        .unwrap_or(image::ImageFormat::Png);
+    // Сразу в RGBA8: 16-битные PNG и TIFF, серые и палитровые исходники дальше
+    // обрабатываются как обычные 8-битные, и кодировщики получают только RGBA8
+    // (или Luma8 после grayscale), которые принимают все они
+    let (img, from_cmyk) = decode_rgba(img_reader, &img_data)?;
+    // Поворот по EXIF до ресайза, чтобы width/height относились к видимой ориентации.
+    // Метаданные в результат не копируются, так что повторного поворота у клиента не будет.
+    let img = apply_orientation(img, metadata::exif_orientation(&img_data));
+    let original_size = img.dimensions();
+    let img = apply_transform(
+        img,
+        params.rotate,
+        params.flip.as_deref().and_then(parse_flip),
+    );
+    let img = match params.crop.as_deref().and_then(parse_crop) {
+        Some((x, y, width, height)) => {
+            let inside = u64::from(x) + u64::from(width) <= u64::from(img.width())
+                && u64::from(y) + u64::from(height) <= u64::from(img.height());
+            if !inside {
+                return Err(AppError::InvalidParam(
+                    "Crop rectangle is outside the image",
+                ));
+            }
+            imageops::crop_imm(&img, x, y, width, height).to_image()
+        }
+        None => img,
+    };
+    let color_convert = params.color_convert == Some(true);
+    // CMYK-профиль к пикселям, уже переведённым в RGB, не подходит
+    let icc_profile = (!from_cmyk
+        && (color_convert || params.strip == Some(false) || params.color_convert == Some(false)))
+    .then(|| metadata::read_icc_profile(&img_data, input_format))
+    .flatten();
+
+    // JPEG не хранит прозрачность: без явного формата и фона отдаём PNG
+    let has_alpha = img.pixels().any(|px| px[3] < u8::MAX);
+    let background = params.background.as_deref().and_then(parse_hex_color);
+    if has_alpha
+        && format == image::ImageFormat::Jpeg
+        && params.format.is_none()
+        && background.is_none()
+    {
+        format = image::ImageFormat::Png;
+    }
 
-    // Целевой размер
-    let dst_width = query.width;
-    let dst_height = query.height;
+    let (width_orig, height_orig) = img.dimensions();
+    let decoded = Instant::now();
+
+    // Целевой размер; без scale обе стороны к этому моменту заполнены в prepare.
+    // Со scale пропорции сохраняются, а стороны не выходят за MAX_OUTPUT_DIMENSION.
+    let (box_width, box_height) = match params.scale {
+        Some(scale) => {
+            let scaled = |side: u32| (f64::from(side) * f64::from(scale)).round().max(1.0) as u32;
+            let (width, height) = (scaled(width_orig), scaled(height_orig));
+            if width.max(height) > max_output_dimension {
+                contain_size(width, height, max_output_dimension, max_output_dimension)
+            } else {
+                (width, height)
+            }
+        }
+        None => (params.width.unwrap_or(1), params.height.unwrap_or(1)),
+    };
+    let fit = params
+        .fit
+        .as_deref()
+        .and_then(parse_fit)
+        .unwrap_or(Fit::Fill);
+    let (dst_width, dst_height) = match fit {
+        Fit::Contain => contain_size(width_orig, height_orig, box_width, box_height),
+        Fit::Fill | Fit::Cover => (box_width, box_height),
+    };
+    let upscales = dst_width > width_orig || dst_height > height_orig;
+    let (dst_width, dst_height) = if upscales && params.allow_upscale != Some(true) {
+        contain_size(dst_width, dst_height, width_orig, height_orig)
+    } else {
+        (dst_width, dst_height)
+    };
     let mut dst_image = Image::new(dst_width, dst_height, fir::PixelType::U8x4);
 
     // Ресайз
+    let algorithm = params
+        .filter
+        .as_deref()
+        .and_then(parse_filter)
+        .unwrap_or(ResizeAlg::Convolution(FilterType::Lanczos3));
+    let mut options = ResizeOptions::new().resize_alg(algorithm);
+    if fit == Fit::Cover {
+        let (mut left, mut top, width, height) =
+            cover_crop(width_orig, height_orig, dst_width, dst_height);
+        if let Some(focal) = params.focal.as_deref().and_then(parse_focal) {
+            (left, top) = focal_window(width_orig, height_orig, focal, (width, height));
+        } else if params.crop.as_deref() == Some(SMART_CROP) {
+            (left, top) = smartcrop::entropy_window(&img, (left, top, width, height));
+        }
+        options = options.crop(left, top, width, height);
+    }
+
+    // Создаем Image для fast_image_resize
+    let src_image = Image::from_vec_u8(
+        width_orig,
+        height_orig,
+        img.into_raw(),
+        fir::PixelType::U8x4,
+    )
+    .map_err(|err| AppError::ResizeFailed(err.to_string()))?;
     let mut resizer = Resizer::new();
-    resizer.resize(&src_image, &mut dst_image, None).unwrap();
+    resizer
+        .resize(&src_image, &mut dst_image, &options)
+        .map_err(|err| AppError::ResizeFailed(err.to_string()))?;
+
+    // Конвертируем обратно в DynamicImage, забирая буфер без копирования
+    let mut img_buffer =
+        ImageBuffer::<Rgba<u8>, _>::from_raw(dst_width, dst_height, dst_image.into_vec())
+            .ok_or_else(|| AppError::ResizeFailed("output buffer size mismatch".into()))?;
+
+    // Перевод в sRGB после ресайза, чтобы обрабатывать меньше пикселей, но до фона
+    // и водяного знака: их цвета уже в sRGB. Неподдерживаемый профиль сохраняется,
+    // а клиент получает X-Image-Warning.
+    let mut color_unconverted = false;
+    let icc_profile = match icc_profile {
+        Some(icc) if color_convert => {
+            color_unconverted = !color::to_srgb(&mut img_buffer, &icc);
+            color_unconverted.then_some(icc)
+        }
+        icc_profile => icc_profile,
+    };
+
+    // Явный фон применяется всегда, для JPEG прозрачные области по умолчанию белые
+    let background = background
+        .or_else(|| (has_alpha && format == image::ImageFormat::Jpeg).then_some(Rgb([u8::MAX; 3])));
+    if let Some(background) = background {
+        flatten_onto(&mut img_buffer, background);
+    }
+    let opaque = !has_alpha || background.is_some();
+    let mut dyn_image = DynamicImage::ImageRgba8(img_buffer);
 
-    // Конвертируем обратно в DynamicImage
-    let buffer = dst_image.buffer();
-    let img_buffer =
-        ImageBuffer::<Rgba<u8>, _>::from_raw(dst_width, dst_height, buffer.to_vec()).unwrap();
-    let dyn_image = DynamicImage::ImageRgba8(img_buffer);
+    // Фильтры после ресайза, чтобы сила зависела от размера результата
+    if let Some(sigma) = params.blur.filter(|sigma| *sigma > 0.0) {
+        dyn_image = dyn_image.blur(sigma.min(MAX_FILTER_SIGMA));
+    }
+    if let Some(sigma) = params.sharpen.filter(|sigma| *sigma > 0.0) {
+        dyn_image = dyn_image.unsharpen(sigma.min(MAX_FILTER_SIGMA), SHARPEN_THRESHOLD);
+    }
+
+    // Дуотон после ресайза и фильтров, но до водяного знака: знак сохраняет свои цвета
+    if let Some(colors) = params.duotone.as_deref().and_then(parse_duotone) {
+        let mut rgba = dyn_image.into_rgba8();
+        apply_duotone(&mut rgba, colors);
+        dyn_image = DynamicImage::ImageRgba8(rgba);
+    }
+
+    // Водяной знак после фильтров, чтобы он оставался чётким
+    if let Some(mark) = params
+        .watermark
+        .as_deref()
+        .and_then(|name| watermarks.get(name))
+    {
+        let position = params
+            .watermark_pos
+            .as_deref()
+            .and_then(watermark::parse_position)
+            .unwrap_or(Position::BottomRight);
+        let mut rgba = dyn_image.into_rgba8();
+        watermark::apply(
+            &mut rgba,
+            &mark,
+            position,
+            params.watermark_opacity.unwrap_or(1.0),
+        );
+        dyn_image = DynamicImage::ImageRgba8(rgba);
+    }
+
+    // Оттенки серого после ресайза. JPEG и PNG без профиля кодируются
+    // одноканальными, остальные кодировщики ниже ожидают RGBA.
+    // RGB-профиль к серому изображению не подходит, поэтому отбрасывается.
+    let mut icc_profile = icc_profile.as_deref();
+    if params.grayscale == Some(true) {
+        let gray = dyn_image.grayscale();
+        icc_profile = None;
+        dyn_image = match format {
+            image::ImageFormat::Jpeg => DynamicImage::ImageLuma8(gray.to_luma8()),
+            image::ImageFormat::Png if opaque => DynamicImage::ImageLuma8(gray.to_luma8()),
+            image::ImageFormat::Png => gray,
+            _ => DynamicImage::ImageRgba8(gray.to_rgba8()),
+        };
+    }
+
+    let resized = Instant::now();
+    let timings = |encoded: Instant| Timings {
+        fetch: None,
+        decode: Some(decoded - started),
+        resize: Some(resized - decoded),
+        encode: Some(encoded - resized),
+    };
+
+    let defaults = JpegOptions::default();
+    let jpeg = JpegOptions {
+        progressive: params.progressive == Some(true),
+        subsampling: params
+            .subsampling
+            .as_deref()
+            .and_then(parse_subsampling)
+            .unwrap_or(defaults.subsampling),
+    };
+    // Кривая переводит только quality из запроса или пресета: DEFAULT_QUALITY_<ФОРМАТ>
+    // уже задан в шкале кодировщика
+    let curve = params
+        .quality_curve
+        .as_deref()
+        .and_then(quality::parse_curve)
+        .unwrap_or(QualityCurve::Linear);
+    let lossless = params.lossless == Some(true);
+    let mut complexity = None;
+    let mut requested_quality = match params.quality.as_deref().and_then(quality::parse_quality) {
+        // Детализация считается по результату ресайза: её и увидит клиент
+        Some(quality::Quality::Auto) if is_lossy(format, lossless) => {
+            let score = quality::complexity(&dyn_image);
+            complexity = Some(score);
+            quality::auto_quality(format, score)
+        }
+        Some(quality::Quality::Value(quality)) => curve.encoder_quality(format, quality),
+        _ => f32::from(default_quality.for_format(format)),
+    };
+    // Save-Data: quality из запроса, пресета или DEFAULT_QUALITY_<ФОРМАТ> минус
+    // SAVE_DATA_QUALITY_REDUCTION, но не ниже SAVE_DATA_MIN_QUALITY; то, что уже
+    // ниже этого порога, не меняется
+    if params.quality_reduction > 0 {
+        requested_quality = (requested_quality - f32::from(params.quality_reduction))
+            .max(SAVE_DATA_MIN_QUALITY.min(requested_quality));
+    }
+    // MIN_QUALITY и MAX_QUALITY действуют на всё, включая пресеты и Save-Data
+    let requested_quality = default_quality.clamp(requested_quality);
+    let output_size = (dyn_image.width(), dyn_image.height());
+    // Исходник выглядит так же, как результат: тот же формат и размер без поворота
+    // по EXIF и ни одного параметра, меняющего пиксели
+    let same_image = params.force_reencode != Some(true)
+        && !from_cmyk
+        && input_format == Some(format)
+        && output_size == size
+        && original_size == size
+        && params.rotate.is_none()
+        && params.flip.is_none()
+        && params.crop.is_none()
+        && params.blur.is_none_or(|sigma| sigma <= 0.0)
+        && params.sharpen.is_none_or(|sigma| sigma <= 0.0)
+        && params.grayscale != Some(true)
+        && params.duotone.is_none()
+        && params.watermark.is_none()
+        && params.background.is_none()
+        && params.color_convert != Some(true);
+    let encode_at = |quality| encode(&dyn_image, format, quality, icc_profile, jpeg, lossless);
+    let Some(max_bytes) = params.max_bytes.filter(|_| is_lossy(format, lossless)) else {
+        let (mut bytes, content_type) = encode_at(requested_quality)?;
+        let mut bytes_saved = None;
+        if content_type == "image/png" && params.lossless_optimize == Some(true) {
+            let optimized =
+                oxipng::optimize_from_memory(&bytes, &oxipng::Options::from_preset(OXIPNG_PRESET))
+                    .map_err(|err| AppError::EncodeFailed(err.to_string()))?;
+            // oxipng не должен вернуть файл больше исходного, но на всякий случай
+            let saved = bytes.len().saturating_sub(optimized.len());
+            if saved > 0 {
+                buffers::recycle(std::mem::replace(&mut bytes, optimized));
+            }
+            bytes_saved = Some(saved);
+        }
+        let output = Output {
+            bytes: buffers::into_bytes(bytes),
+            content_type,
+            quality: is_lossy(format, lossless).then_some(requested_quality),
+            complexity,
+            bytes_saved,
+            original_size,
+            output_size,
+            last_modified: None,
+            passthrough: false,
+            color_unconverted,
+            fallback: false,
+            original: false,
+            timings: timings(Instant::now()),
+        };
+        return Ok(keep_original(output, img_data, input_format, same_image));
+    };
+
+    // Бинарный поиск наибольшего качества, при котором результат укладывается
+    // в max_bytes. Если не укладывается и на MIN_AUTO_QUALITY (или MIN_QUALITY,
+    // если он выше), отдаём этот вариант.
+    // Отброшенные варианты возвращаются в пул буферов.
+    let mut best = encode_at(requested_quality)?;
+    let mut quality = requested_quality;
+    if best.0.len() > max_bytes {
+        let floor = MIN_AUTO_QUALITY.max(f32::from(default_quality.min));
+        let (mut low, mut high) = (floor.min(requested_quality), requested_quality);
+        let candidate = encode_at(low)?;
+        buffers::recycle(std::mem::replace(&mut best, candidate).0);
+        quality = low;
+        if best.0.len() <= max_bytes {
+            // low укладывается, high - нет; проверяются целые значения между ними
+            while high - low > 1.0 {
+                let mid = (low + (high - low) / 2.0).round();
+                let candidate = encode_at(mid)?;
+                if candidate.0.len() <= max_bytes {
+                    buffers::recycle(std::mem::replace(&mut best, candidate).0);
+                    (low, quality) = (mid, mid);
+                } else {
+                    buffers::recycle(candidate.0);
+                    high = mid;
+                }
+            }
+        }
+    }
+    let output = Output {
+        bytes: buffers::into_bytes(best.0),
+        content_type: best.1,
+        quality: Some(quality),
+        complexity,
+        bytes_saved: None,
+        original_size,
+        output_size,
+        last_modified: None,
+        passthrough: false,
+        color_unconverted,
+        fallback: false,
+        original: false,
+        timings: timings(Instant::now()),
+    };
+    Ok(keep_original(output, img_data, input_format, same_image))
+}
 
-    // Конвертируем в bytes с нужным форматом
-    let mut bytes = Vec::new();
+// Отдаёт исходник вместо результата, если тот больше, а `same_image` - исходник
+// можно показать вместо результата. Исходник с EXIF и другими метаданными не
+// отдаётся: при перекодировании они удаляются всегда.
+fn keep_original(
+    output: Output,
+    img_data: Vec<u8>,
+    input_format: Option<image::ImageFormat>,
+    same_image: bool,
+) -> Output {
+    if !same_image
+        || output.bytes.len() <= img_data.len()
+        || metadata::has_metadata(&img_data, input_format)
+    {
+        return output;
+    }
+    Output {
+        bytes: img_data.into(),
+        quality: None,
+        complexity: None,
+        bytes_saved: None,
+        original: true,
+        ..output
+    }
+}
+
+// Настройки JPEG, которых нет у кодировщика image
+#[derive(Clone, Copy)]
+struct JpegOptions {
+    progressive: bool,
+    subsampling: jpeg_encoder::SamplingFactor,
+}
+
+impl Default for JpegOptions {
+    // Без параметра subsampling - 4:2:0, как у большинства оптимизаторов:
+    // цветность вдвое реже почти не видна, а файл заметно меньше
+    fn default() -> Self {
+        JpegOptions {
+            progressive: false,
+            subsampling: jpeg_encoder::SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+// Кодировщик JPEG в image умеет только baseline 4:4:4, поэтому JPEG пишет jpeg-encoder.
+// Оптимизированные таблицы Хаффмана добавляют проход, но окупаются размером.
+fn encode_jpeg(
+    dyn_image: &DynamicImage,
+    quality: u8,
+    options: JpegOptions,
+    bytes: &mut Vec<u8>,
+) -> Result<(), AppError> {
+    let encode_failed = |err: jpeg_encoder::EncodingError| AppError::EncodeFailed(err.to_string());
+    let too_large = || AppError::EncodeFailed("image is too large for JPEG".into());
+    let width = u16::try_from(dyn_image.width()).map_err(|_| too_large())?;
+    let height = u16::try_from(dyn_image.height()).map_err(|_| too_large())?;
+
+    let mut encoder = jpeg_encoder::Encoder::new(bytes, quality.clamp(1, 100));
+    encoder.set_progressive(options.progressive);
+    encoder.set_optimized_huffman_tables(true);
+    encoder.set_sampling_factor(options.subsampling);
+    match dyn_image {
+        DynamicImage::ImageLuma8(img) => encoder
+            .encode(img.as_raw(), width, height, jpeg_encoder::ColorType::Luma)
+            .map_err(encode_failed)?,
+        DynamicImage::ImageRgba8(img) => encoder
+            .encode(img.as_raw(), width, height, jpeg_encoder::ColorType::Rgba)
+            .map_err(encode_failed)?,
+        img => encoder
+            .encode(&img.to_rgb8(), width, height, jpeg_encoder::ColorType::Rgb)
+            .map_err(encode_failed)?,
+    }
+    Ok(())
+}
+
+// Форматы, у которых quality влияет на размер; у WebP без потерь не влияет
+fn is_lossy(format: image::ImageFormat, lossless: bool) -> bool {
+    match format {
+        image::ImageFormat::Jpeg | image::ImageFormat::Avif => true,
+        image::ImageFormat::WebP => !lossless,
+        _ => false,
+    }
+}
+
+// Кодирует результат в нужный формат
+// quality дробное только для AVIF, JPEG и WebP получают ближайшее целое
+fn encode(
+    dyn_image: &DynamicImage,
+    format: image::ImageFormat,
+    quality: f32,
+    icc_profile: Option<&[u8]>,
+    jpeg: JpegOptions,
+    lossless: bool,
+) -> Result<(Vec<u8>, &'static str), AppError> {
+    let quality = quality.clamp(1.0, 100.0);
+    let rounded = quality.round() as u8;
+    let (dst_width, dst_height) = (dyn_image.width(), dyn_image.height());
+    let encode_failed = |err: image::ImageError| AppError::EncodeFailed(err.to_string());
+    // Буфер из пула; кодировщики, которые возвращают свой Vec, отдают его обратно
+    let mut bytes = buffers::take();
     let content_type = match format {
         image::ImageFormat::Png => {
-            dyn_image
-                .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)
-                .unwrap();
+            match icc_profile {
+                Some(icc) => metadata::encode_png_with_icc(
+                    dyn_image.as_bytes(),
+                    dst_width,
+                    dst_height,
+                    icc,
+                    &mut bytes,
+                )
+                .map_err(|err| AppError::EncodeFailed(err.to_string()))?,
+                None => dyn_image
+                    .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)
+                    .map_err(encode_failed)?,
+            }
             "image/png"
         }
         image::ImageFormat::Jpeg => {
-            dyn_image
-                .write_to(
-                    &mut Cursor::new(&mut bytes),
-                    ImageOutputFormat::Jpeg(query.quality),
-                )
-                .unwrap();
+            encode_jpeg(dyn_image, rounded, jpeg, &mut bytes)?;
+            if let Some(icc) = icc_profile {
+                bytes = metadata::embed_icc_jpeg(bytes, icc);
+            }
             "image/jpeg"
         }
+        image::ImageFormat::WebP => {
+            let encoder = if lossless {
+                WebPEncoder::new_lossless(&mut bytes)
+            } else {
+                // Lossy WebP в image 0.24 помечен deprecated, но libwebp его поддерживает
+                #[allow(deprecated)]
+                WebPEncoder::new_with_quality(&mut bytes, WebPQuality::lossy(rounded))
+            };
+            dyn_image
+                .write_with_encoder(encoder)
+                .map_err(encode_failed)?;
+            "image/webp"
+        }
+        image::ImageFormat::Avif => {
+            // Пиксели RGBA8 передаются ravif как есть, без копии. Свой буфер
+            // для результата ravif не принимает: его Vec попадёт в пул после отправки.
+            let encoded = ravif::Encoder::new()
+                .with_quality(quality)
+                .with_speed(AVIF_SPEED)
+                .encode_rgba(Img::new(
+                    dyn_image.as_bytes().as_rgba(),
+                    dst_width as usize,
+                    dst_height as usize,
+                ))
+                .map_err(|err| AppError::EncodeFailed(err.to_string()))?;
+            buffers::recycle(std::mem::replace(&mut bytes, encoded.avif_file));
+            "image/avif"
+        }
         _ => {
             dyn_image
                 .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)
-                .unwrap();
+                .map_err(encode_failed)?;
             "image/png"
         }
     };
 
-    HttpResponse::Ok().content_type(content_type).body(bytes)
+    Ok((bytes, content_type))
+}
+
+// Готовое изображение
+#[derive(Clone)]
+struct Output {
+    bytes: web::Bytes,
+    content_type: &'static str,
+    // Итоговое quality в шкале кодировщика (после MIN_QUALITY и MAX_QUALITY
+    // и подбора под max_bytes); None - формат без потерь
+    quality: Option<f32>,
+    // Детализация, по которой выбрано quality=auto, для X-Image-Complexity
+    complexity: Option<f32>,
+    // Сколько байт сэкономил oxipng
+    bytes_saved: Option<usize>,
+    // Размер исходника с учётом EXIF-ориентации и размер результата
+    original_size: (u32, u32),
+    output_size: (u32, u32),
+    // Last-Modified исходника, если его сообщил источник
+    last_modified: Option<HttpDate>,
+    // Анимированный исходник отдан без изменений
+    passthrough: bool,
+    // color_convert=true, но профиль исходника не матричный: цвета не переведены
+    color_unconverted: bool,
+    // Вместо исходника отдано изображение из fallback_url
+    fallback: bool,
+    // Отдан исходник: перекодированный результат оказался больше
+    original: bool,
+    // Длительность этапов для Server-Timing
+    timings: Timings,
+}
+
+// Сколько заняли этапы; None - этапа не было (upload без загрузки,
+// анимированный исходник без перекодирования)
+#[derive(Clone, Copy, Default)]
+struct Timings {
+    fetch: Option<Duration>,
+    // Декодирование, поворот и crop
+    decode: Option<Duration>,
+    // Ресайз, цвет, фон, фильтры и водяной знак
+    resize: Option<Duration>,
+    // Кодирование, включая подбор под max_bytes и oxipng
+    encode: Option<Duration>,
+}
+
+impl Timings {
+    // Значение Server-Timing: `fetch;dur=12.5, decode;dur=3.1, ...` в миллисекундах
+    fn header_value(&self) -> Option<String> {
+        let phases = [
+            ("fetch", self.fetch),
+            ("decode", self.decode),
+            ("resize", self.resize),
+            ("encode", self.encode),
+        ];
+        let value = phases
+            .iter()
+            .filter_map(|(name, duration)| {
+                duration
+                    .map(|duration| format!("{name};dur={:.1}", duration.as_secs_f64() * 1000.0))
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        (!value.is_empty()).then_some(value)
+    }
+}
+
+// Результат обработки, который можно раздать нескольким ожидающим запросам
+type Processed = Result<Output, AppError>;
+
+// Скачанный исходник
+struct Source {
+    bytes: Vec<u8>,
+    // Last-Modified источника
+    last_modified: Option<HttpDate>,
+    // Формат по Content-Type; application/octet-stream и прочие не-image типы дают None
+    format_hint: Option<image::ImageFormat>,
+}
+
+// Формат по MIME-типу без параметров (`image/png; charset=...`)
+fn format_from_mime(mime: &str) -> Option<image::ImageFormat> {
+    let mime = mime.split(';').next().unwrap_or_default().trim();
+    image::ImageFormat::from_mime_type(mime.to_ascii_lowercase())
+}
+
+// Читает исходник file:// из LOCAL_ASSET_DIR; `base` уже разрешён через canonicalize
+async fn read_local_asset(base: &Path, url: &str, limit: usize) -> Result<Source, AppError> {
+    let path = fetch::local_asset_path(base, url).ok_or(AppError::InvalidUrl)?;
+    let io_error = |err: std::io::Error| match err.kind() {
+        std::io::ErrorKind::NotFound => AppError::SourceNotFound,
+        _ => AppError::FetchFailed,
+    };
+    // Симлинк внутри каталога может вести наружу, поэтому сравнивается настоящий путь
+    let path = tokio::fs::canonicalize(&path).await.map_err(io_error)?;
+    if !path.starts_with(base) {
+        return Err(AppError::HostNotAllowed);
+    }
+    let metadata = tokio::fs::metadata(&path).await.map_err(io_error)?;
+    if !metadata.is_file() {
+        return Err(AppError::SourceNotFound);
+    }
+    if metadata.len() > limit as u64 {
+        return Err(AppError::TooLarge);
+    }
+    let bytes = tokio::fs::read(&path).await.map_err(io_error)?;
+    if bytes.len() > limit {
+        return Err(AppError::TooLarge);
+    }
+    Ok(Source {
+        bytes,
+        last_modified: metadata.modified().ok().map(HttpDate::from),
+        format_hint: image::ImageFormat::from_path(&path).ok(),
+    })
+}
+
+// Скачивает исходник по URL с проверкой хоста и ограничением размера
+async fn fetch_source(
+    client: &reqwest::Client,
+    config: &Config,
+    url: &str,
+) -> Result<Source, AppError> {
+    // data: URI несёт изображение в себе, сеть не нужна
+    if url.starts_with("data:") {
+        let (mime, bytes) = fetch::decode_data_uri(url).ok_or(AppError::InvalidDataUri)?;
+        if bytes.len() > config.max_download_bytes {
+            return Err(AppError::TooLarge);
+        }
+        return Ok(Source {
+            bytes,
+            last_modified: None,
+            format_hint: format_from_mime(mime),
+        });
+    }
+    if url.starts_with("file:") {
+        let base = config
+            .local_asset_dir
+            .as_deref()
+            .ok_or(AppError::InvalidUrl)?;
+        return read_local_asset(base, url, config.max_download_bytes).await;
+    }
+    // Проверка хоста до запроса, защита от обращений во внутреннюю сеть
+    let url = fetch::check_url(url, config.allowed_hosts.as_deref()).map_err(|err| match err {
+        UrlRejection::Malformed => AppError::InvalidUrl,
+        UrlRejection::Forbidden => AppError::HostNotAllowed,
+    })?;
+    let log_url = access_log::redact_url(url.as_str(), config.log_redact_urls);
+    // Обрывы соединения и 5xx повторяем с растущей паузой, 4xx и таймауты - нет
+    let mut attempt = 1;
+    let resp = loop {
+        let result = fetch::get(client, config, &url).await;
+        let retryable = match &result {
+            Ok(resp) => resp.status().is_server_error(),
+            Err(GetError::Request(err)) => {
+                !fetch::is_blocked(err)
+                    && !err.is_timeout()
+                    && (err.is_connect() || err.is_request())
+            }
+            Err(GetError::Blocked | GetError::TooManyRedirects) => false,
+        };
+        if !retryable || attempt >= config.fetch_retry_attempts {
+            break result;
+        }
+        // Пауза не длиннее FETCH_TIMEOUT_SECONDS: дольше ждать повтора нет смысла
+        let delay = Duration::from_millis(config.fetch_retry_base_delay_ms)
+            .saturating_mul(1 << (attempt - 1))
+            .min(Duration::from_secs(config.fetch_timeout_seconds));
+        match &result {
+            Ok(resp) => {
+                tracing::debug!(url = %log_url, status = %resp.status(), attempt, ?delay, "retrying upstream fetch")
+            }
+            Err(err) => {
+                tracing::debug!(url = %log_url, error = %err, attempt, ?delay, "retrying upstream fetch")
+            }
+        }
+        time::sleep(delay).await;
+        attempt += 1;
+    };
+    let resp = resp.map_err(|err| {
+        match &err {
+            GetError::Blocked => return AppError::HostNotAllowed,
+            GetError::Request(err) if fetch::is_blocked(err) => return AppError::HostNotAllowed,
+            _ => {}
+        }
+        tracing::warn!(url = %log_url, error = %err, "upstream fetch failed");
+        counter!(monitoring::FETCH_FAILURES_TOTAL).increment(1);
+        match err {
+            GetError::Request(err) if err.is_timeout() => AppError::FetchTimeout,
+            _ => AppError::FetchFailed,
+        }
+    })?;
+    // 404 источника отдаём как есть, прочие ошибки источника - 502
+    let status = resp.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(AppError::SourceNotFound);
+    }
+    if status.is_client_error() || status.is_server_error() {
+        tracing::warn!(url = %log_url, %status, "upstream fetch failed");
+        counter!(monitoring::FETCH_FAILURES_TOTAL).increment(1);
+        return Err(AppError::FetchFailed);
+    }
+    let last_modified = resp
+        .headers()
+        .get(reqwest::header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<HttpDate>().ok());
+    let format_hint = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(format_from_mime);
+    let bytes = fetch::read_body(resp, config.max_download_bytes)
+        .await
+        .map_err(|err| match err {
+            BodyError::TooLarge => AppError::TooLarge,
+            BodyError::Read => {
+                tracing::warn!(url = %log_url, "upstream body read failed");
+                counter!(monitoring::FETCH_FAILURES_TOTAL).increment(1);
+                AppError::FetchFailed
+            }
+            BodyError::Timeout => {
+                tracing::warn!(url = %log_url, "upstream body read timed out");
+                counter!(monitoring::FETCH_FAILURES_TOTAL).increment(1);
+                AppError::FetchTimeout
+            }
+        })?;
+    Ok(Source {
+        bytes,
+        last_modified,
+        format_hint,
+    })
+}
+
+// Читает файл из multipart-загрузки
+async fn read_upload(payload: Option<Multipart>) -> Result<Vec<u8>, AppError> {
+    let mut payload = payload.ok_or(AppError::NoImage)?;
+    let mut img_data = Vec::new();
+    while let Some(item) = payload.next().await {
+        // После ошибки multipart-поток нельзя опрашивать дальше
+        let mut field = item.map_err(|_| AppError::UploadFailed)?;
+
+        while let Some(chunk) = field.next().await {
+            let bytes = chunk.map_err(|_| AppError::UploadFailed)?;
+            img_data.extend_from_slice(&bytes);
+        }
+    }
+    Ok(img_data)
+}
+
+// Читает тело запроса не больше `limit` байт
+async fn read_raw_body(mut payload: web::Payload, limit: usize) -> Result<Vec<u8>, AppError> {
+    let mut img_data = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        let bytes = chunk.map_err(|_| AppError::UploadFailed)?;
+        if img_data.len() + bytes.len() > limit {
+            return Err(AppError::TooLarge);
+        }
+        img_data.extend_from_slice(&bytes);
+    }
+    Ok(img_data)
+}
+
+// Декодирование, ресайз и кодирование нагружают CPU, поэтому уходят в blocking-пул
+async fn process_blocking(
+    img_data: Vec<u8>,
+    format_hint: Option<image::ImageFormat>,
+    params: ResizeParams,
+    requested_format: Option<image::ImageFormat>,
+    watermarks: web::Data<Watermarks>,
+    pool: web::Data<ProcessingPool>,
+    config: &Config,
+) -> Processed {
+    if img_data.is_empty() {
+        return Err(AppError::NoImage);
+    }
+    if !params.format_candidates.is_empty() {
+        return process_best(img_data, format_hint, params, watermarks, pool, config).await;
+    }
+
+    // Время считается с момента, когда задача получила место в пуле, без ожидания
+    // в очереди за PROCESSING_THREADS. По таймауту клиент получает 408, но сама
+    // задача доработает в фоне.
+    let max_input_pixels = config.max_input_pixels;
+    let max_output_dimension = config.max_output_dimension;
+    let default_quality = config.default_quality;
+    let timeout = Duration::from_secs(config.processing_timeout_seconds);
+    let memory = pool.memory();
+    let slot = pool.acquire().await;
+    let work = slot.run(move || {
+        let started = Instant::now();
+        let processed = process_image(
+            img_data,
+            &params,
+            requested_format,
+            &watermarks,
+            max_input_pixels,
+            max_output_dimension,
+            format_hint,
+            default_quality,
+            &memory,
+        );
+        (processed, started.elapsed())
+    });
+    let (processed, elapsed) = time::timeout(timeout, work)
+        .await
+        .map_err(|_| {
+            tracing::warn!("image processing timed out after {}s", timeout.as_secs());
+            AppError::ProcessingTimeout
+        })?
+        .map_err(|_| AppError::ProcessingFailed)?;
+    histogram!(monitoring::PROCESSING_SECONDS).record(elapsed.as_secs_f64());
+    tracing::Span::current().record("processing_ms", elapsed.as_millis() as u64);
+    processed.inspect_err(|err| {
+        if let AppError::DecodeFailed(reason) = err {
+            tracing::warn!(%reason, "source image decode failed");
+            counter!(monitoring::DECODE_FAILURES_TOTAL).increment(1);
+        }
+    })
+}
+
+// format=best: по задаче на каждый формат-кандидат, параллельно в пределах
+// PROCESSING_THREADS, и самый маленький результат. Стоит как отдельный запрос
+// на каждый формат: CPU, память и место в пуле, а с AVIF ответ приходит не раньше,
+// чем закончится его кодирование, обычно в разы дольше JPEG и WebP.
+async fn process_best(
+    img_data: Vec<u8>,
+    format_hint: Option<image::ImageFormat>,
+    mut params: ResizeParams,
+    watermarks: web::Data<Watermarks>,
+    pool: web::Data<ProcessingPool>,
+    config: &Config,
+) -> Processed {
+    let candidates = std::mem::take(&mut params.format_candidates);
+    let results = futures_util::future::join_all(candidates.iter().map(|format| {
+        Box::pin(process_blocking(
+            img_data.clone(),
+            format_hint,
+            params.clone(),
+            Some(*format),
+            watermarks.clone(),
+            pool.clone(),
+            config,
+        ))
+    }))
+    .await;
+    let mut best: Option<Output> = None;
+    let mut first_err = None;
+    for result in results {
+        match result {
+            Ok(output)
+                if best
+                    .as_ref()
+                    .is_none_or(|best| output.bytes.len() < best.bytes.len()) =>
+            {
+                best = Some(output)
+            }
+            Ok(_) => {}
+            Err(err) => {
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    let best = best.ok_or_else(|| first_err.unwrap_or(AppError::ProcessingFailed))?;
+    tracing::debug!(
+        ?candidates,
+        served = best.content_type,
+        "format=best picked the smallest result"
+    );
+    Ok(best)
+}
+
+// Проверяет параметры до загрузки изображения и выбирает формат вывода:
+// без параметра `format` пробуем договориться через Accept, затем DEFAULT_FORMAT.
+// С `save_data` снижается quality и без `format` выбирается самый компактный формат.
+// Слишком большие width и height урезаются до MAX_OUTPUT_DIMENSION,
+// иначе один запрос может занять гигабайты под буфер результата.
+fn prepare(
+    params: &mut ResizeParams,
+    accept: Option<web::Header<Accept>>,
+    save_data: bool,
+    config: &Config,
+    watermarks: &Watermarks,
+    presets: &Presets,
+) -> Result<Option<image::ImageFormat>, AppError> {
+    if let Some(size) = params.size.as_deref() {
+        let (width, height) =
+            parse_size(size).ok_or(AppError::InvalidParam("Size must be W, WxH or xH"))?;
+        if params.width.is_some() || params.height.is_some() {
+            tracing::warn!(size, "both size and width/height given, using width/height");
+        }
+        params.width = params.width.or(width);
+        params.height = params.height.or(height);
+    }
+    if let Some(scale) = params.scale {
+        if !(MIN_SCALE..=MAX_SCALE).contains(&scale) {
+            return Err(AppError::InvalidParam("Scale must be from 0.01 to 4"));
+        }
+        if params.width.is_some() || params.height.is_some() {
+            tracing::warn!(
+                scale,
+                "both scale and width/height given, using width/height"
+            );
+            params.scale = None;
+        }
+    }
+    // Со scale размер считается в process_image по исходнику
+    if params.scale.is_none() {
+        let (width, height) = match (params.width, params.height) {
+            (None, None) => {
+                return Err(AppError::InvalidParam("Width, height or scale is required"))
+            }
+            (Some(0), _) | (_, Some(0)) => {
+                return Err(AppError::InvalidParam("Width and height must be positive"))
+            }
+            (Some(width), Some(height)) => (width, height),
+            // Одна сторона: вписываем в рамку, где вторая сторона предельная,
+            // так пропорции сохраняются, а результат не выходит за MAX_OUTPUT_DIMENSION
+            (width, height) => {
+                params.fit = Some("contain".to_string());
+                (
+                    width.unwrap_or(config.max_output_dimension),
+                    height.unwrap_or(config.max_output_dimension),
+                )
+            }
+        };
+        params.width = Some(width.min(config.max_output_dimension));
+        params.height = Some(height.min(config.max_output_dimension));
+    }
+
+    // Пресет заполняет только то, что не задано в запросе
+    if let Some(name) = params.preset.as_deref() {
+        let preset = presets
+            .get(name)
+            .ok_or(AppError::InvalidParam("Unknown preset"))?
+            .clone();
+        params.quality = params.quality.take().or(preset.quality);
+        params.format = params.format.take().or(preset.format);
+        params.filter = params.filter.take().or(preset.filter);
+    }
+
+    let accept = accept.map(web::Header::into_inner);
+    // Дальше format=best ведёт себя как запрос без format: ответ зависит от Accept,
+    // а JPEG для изображения с прозрачностью заменяется на PNG
+    if params
+        .format
+        .as_deref()
+        .is_some_and(|value| value.eq_ignore_ascii_case(BEST_FORMAT))
+    {
+        params.format = None;
+        params.format_candidates = best_candidates(accept.as_ref());
+    }
+    let requested_format = match params.format.as_deref() {
+        Some(value) => match parse_output_format(value) {
+            // Без Accept клиент принимает что угодно, понижать нечего
+            Some(format) => match accept.as_ref() {
+                Some(accept)
+                    if params.strict_accept == Some(true) && !accepts_format(accept, format) =>
+                {
+                    // JPEG показывают даже самые старые клиенты
+                    let downgraded = negotiate_format(accept).unwrap_or(image::ImageFormat::Jpeg);
+                    tracing::info!(
+                        requested = ?format,
+                        served = ?downgraded,
+                        "requested format not accepted by client, downgrading"
+                    );
+                    Some(downgraded)
+                }
+                _ => Some(format),
+            },
+            None => return Err(AppError::InvalidParam("Unsupported output format")),
+        },
+        None => {
+            let negotiated = if save_data {
+                accept.as_ref().and_then(efficient_format)
+            } else {
+                accept.as_ref().and_then(negotiate_format)
+            };
+            negotiated.or(config.default_format)
+        }
+    };
+    if save_data {
+        params.quality_reduction = config.save_data_quality_reduction;
+    }
+    if params
+        .quality
+        .as_deref()
+        .is_some_and(|value| quality::parse_quality(value).is_none())
+    {
+        return Err(AppError::InvalidParam("Quality must be a number or auto"));
+    }
+    if params
+        .quality_curve
+        .as_deref()
+        .is_some_and(|value| quality::parse_curve(value).is_none())
+    {
+        return Err(AppError::InvalidParam(
+            "Quality curve must be linear or perceptual",
+        ));
+    }
+    if params
+        .background
+        .as_deref()
+        .is_some_and(|value| parse_hex_color(value).is_none())
+    {
+        return Err(AppError::InvalidParam("Invalid background color"));
+    }
+    if params
+        .duotone
+        .as_deref()
+        .is_some_and(|value| parse_duotone(value).is_none())
+    {
+        return Err(AppError::InvalidParam(
+            "Duotone must be two hex colors, shadows-highlights",
+        ));
+    }
+    if params
+        .filter
+        .as_deref()
+        .is_some_and(|value| parse_filter(value).is_none())
+    {
+        return Err(AppError::InvalidParam("Unsupported resize filter"));
+    }
+    if params
+        .fit
+        .as_deref()
+        .is_some_and(|value| parse_fit(value).is_none())
+    {
+        return Err(AppError::InvalidParam("Unsupported fit mode"));
+    }
+    if params
+        .subsampling
+        .as_deref()
+        .is_some_and(|value| parse_subsampling(value).is_none())
+    {
+        return Err(AppError::InvalidParam(
+            "Subsampling must be 444, 422 or 420",
+        ));
+    }
+    if params
+        .rotate
+        .is_some_and(|angle| !matches!(angle, 90 | 180 | 270))
+    {
+        return Err(AppError::InvalidParam("Rotation must be 90, 180 or 270"));
+    }
+    if params
+        .flip
+        .as_deref()
+        .is_some_and(|value| parse_flip(value).is_none())
+    {
+        return Err(AppError::InvalidParam(
+            "Flip must be horizontal or vertical",
+        ));
+    }
+    if params
+        .crop
+        .as_deref()
+        .is_some_and(|value| value != SMART_CROP && parse_crop(value).is_none())
+    {
+        return Err(AppError::InvalidParam("Crop must be x,y,w,h or smart"));
+    }
+    if params
+        .focal
+        .as_deref()
+        .is_some_and(|value| parse_focal(value).is_none())
+    {
+        return Err(AppError::InvalidParam(
+            "Focal point must be x,y with both from 0 to 1",
+        ));
+    }
+    if [params.blur, params.sharpen]
+        .into_iter()
+        .flatten()
+        .any(|sigma| !(sigma >= 0.0 && sigma.is_finite()))
+    {
+        return Err(AppError::InvalidParam(
+            "Filter sigma must be a non-negative number",
+        ));
+    }
+    if params.max_bytes == Some(0) {
+        return Err(AppError::InvalidParam("max_bytes must be positive"));
+    }
+    if params
+        .watermark
+        .as_deref()
+        .is_some_and(|name| watermarks.get(name).is_none())
+    {
+        return Err(AppError::InvalidParam("Unknown watermark"));
+    }
+    if params
+        .watermark_pos
+        .as_deref()
+        .is_some_and(|value| watermark::parse_position(value).is_none())
+    {
+        return Err(AppError::InvalidParam("Unsupported watermark position"));
+    }
+    if params
+        .watermark_opacity
+        .is_some_and(|opacity| !(0.0..=1.0).contains(&opacity))
+    {
+        return Err(AppError::InvalidParam(
+            "Watermark opacity must be between 0 and 1",
+        ));
+    }
+    Ok(requested_format)
+}
+
+// Ответ с готовым изображением или 304, если у клиента та же версия
+fn respond(
+    req: &HttpRequest,
+    output: Output,
+    vary_accept: bool,
+    max_age: u32,
+    config: &Config,
+) -> HttpResponse {
+    let Output {
+        bytes,
+        content_type,
+        quality,
+        complexity,
+        bytes_saved,
+        original_size,
+        output_size,
+        last_modified,
+        passthrough,
+        color_unconverted,
+        fallback,
+        original,
+        timings,
+    } = output;
+    tracing::Span::current().record("format", content_type);
+    if !req.extensions().contains::<CacheStatus>() {
+        req.extensions_mut().insert(CacheStatus::Miss);
+    }
+
+    // ETag по содержимому. If-None-Match сравнивается слабо, как требует RFC 9110,
+    // и если он есть, If-Modified-Since не рассматривается.
+    let etag = EntityTag::new_strong(format!("{:x}", Sha1::digest(&bytes)));
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => match (req.get_header::<IfModifiedSince>(), last_modified) {
+            (Some(IfModifiedSince(since)), Some(modified)) => modified <= since,
+            _ => false,
+        },
+    };
+
+    let range = (!not_modified)
+        .then(|| byte_range(req, &etag, last_modified, bytes.len() as u64))
+        .flatten();
+
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else if range.is_some() {
+        HttpResponse::PartialContent()
+    } else {
+        HttpResponse::Ok()
+    };
+    response.insert_header(header::ETag(etag));
+    // Этапы обработки для devtools. Объединённый запрос получает этапы того,
+    // кто выполнил работу, с пометкой coalesced.
+    let mut server_timing = timings.header_value();
+    if matches!(
+        req.extensions().get::<CacheStatus>(),
+        Some(CacheStatus::Coalesced)
+    ) {
+        let coalesced = "coalesced;desc=\"shared with a concurrent request\"";
+        server_timing = Some(match server_timing {
+            Some(phases) => format!("{coalesced}, {phases}"),
+            None => coalesced.to_string(),
+        });
+    }
+    if let Some(value) = server_timing {
+        response.insert_header(("Server-Timing", value));
+    }
+    if let Some(modified) = last_modified {
+        response.insert_header(LastModified(modified));
+    }
+    // Запасное изображение не кэшируется надолго: источник может скоро ожить
+    response.insert_header(if fallback {
+        cache_control(0, 0)
+    } else {
+        cache_control(max_age, config.stale_while_revalidate_seconds)
+    });
+    if vary_accept {
+        // Ответ зависит от Accept, кэши не должны смешивать варианты
+        response.insert_header((header::VARY, "Accept"));
+    }
+    if config.save_data_quality_reduction > 0 {
+        response.append_header((header::VARY, "Save-Data"));
+    }
+    if not_modified {
+        return response.finish();
+    }
+    response.content_type(content_type);
+    if let Some(quality) = quality {
+        response.insert_header(("X-Quality-Used", quality.to_string()));
+    }
+    if let Some(complexity) = complexity {
+        response.insert_header(("X-Image-Complexity", format!("{complexity:.3}")));
+    }
+    if passthrough {
+        response.insert_header((
+            "X-Image-Warning",
+            "animated source returned unchanged, transformations were not applied",
+        ));
+    }
+    if color_unconverted {
+        response.append_header((
+            "X-Image-Warning",
+            "unsupported ICC profile, colors were not converted to sRGB",
+        ));
+    }
+    if fallback {
+        response.insert_header(("X-Fallback", "true"));
+    }
+    if original {
+        response.insert_header(("X-Optimized", "false"));
+    }
+    if let Some(saved) = bytes_saved {
+        response.insert_header(("X-Bytes-Saved", saved.to_string()));
+    }
+    // Размеры для вёрстки без повторного запроса
+    response.insert_header(("X-Original-Width", original_size.0.to_string()));
+    response.insert_header(("X-Original-Height", original_size.1.to_string()));
+    response.insert_header(("X-Output-Width", output_size.0.to_string()));
+    response.insert_header(("X-Output-Height", output_size.1.to_string()));
+    response.insert_header((header::ACCEPT_RANGES, "bytes"));
+    let bytes = match range {
+        Some((start, end)) => {
+            response.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(bytes.len() as u64),
+            }));
+            bytes.slice(start as usize..=end as usize)
+        }
+        None => bytes,
+    };
+    // Content-Length actix выставляет сам по длине тела
+    counter!(monitoring::BYTES_SERVED_TOTAL).increment(bytes.len() as u64);
+    response.body(bytes)
+}
+
+// Диапазон для 206 из Range, чтобы клиент мог докачать прерванную загрузку.
+// Результат детерминирован, поэтому диапазон берётся из только что собранного
+// ответа. С If-Range диапазон отдаётся, только если совпадает сильный ETag или
+// Last-Modified, иначе - весь ответ. Несколько диапазонов и неудовлетворимый
+// диапазон тоже дают обычный 200 со всем телом.
+fn byte_range(
+    req: &HttpRequest,
+    etag: &EntityTag,
+    last_modified: Option<HttpDate>,
+    len: u64,
+) -> Option<(u64, u64)> {
+    let Range::Bytes(ranges) = req.get_header::<Range>()? else {
+        return None;
+    };
+    let matches = match req.get_header::<IfRange>() {
+        None => true,
+        Some(IfRange::EntityTag(tag)) => tag.strong_eq(etag),
+        Some(IfRange::Date(date)) => last_modified == Some(date),
+    };
+    match ranges.as_slice() {
+        [range] if matches => range.to_satisfiable_range(len),
+        _ => None,
+    }
+}
+
+// max-age ответа: cache_ttl из запроса, урезанный до CACHE_TTL_MAX_SECONDS,
+// иначе CACHE_MAX_AGE_SECONDS
+fn cache_max_age(params: &ResizeParams, config: &Config) -> u32 {
+    params
+        .cache_ttl
+        .map_or(config.cache_max_age_seconds, |ttl| {
+            ttl.min(u64::from(config.cache_ttl_max_seconds)) as u32
+        })
+}
+
+// Cache-Control с заданным max-age и STALE_WHILE_REVALIDATE_SECONDS;
+// max-age 0 - кэшировать только с проверкой
+fn cache_control(max_age: u32, stale_while_revalidate: u32) -> CacheControl {
+    if max_age == 0 {
+        return CacheControl(vec![CacheDirective::NoCache]);
+    }
+    let mut directives = vec![CacheDirective::Public, CacheDirective::MaxAge(max_age)];
+    // Своего кэша у сервера нет: фоновое обновление выполняет CDN или браузер
+    if stale_while_revalidate > 0 {
+        directives.push(CacheDirective::Extension(
+            "stale-while-revalidate".to_string(),
+            Some(stale_while_revalidate.to_string()),
+        ));
+    }
+    CacheControl(directives)
+}
+
+// Скачивание по URL и обработка с объединением одинаковых одновременных запросов
+// и запасным изображением из fallback_url. Общая для /resize и /optimize/batch.
+// Второе значение - результат взят у такого же одновременного запроса.
+#[allow(clippy::too_many_arguments)]
+async fn process_url(
+    url: String,
+    mut params: ResizeParams,
+    requested_format: Option<image::ImageFormat>,
+    config: web::Data<Config>,
+    client: web::Data<reqwest::Client>,
+    inflight: web::Data<Inflight<Processed>>,
+    failures: web::Data<FailedFetches>,
+    watermarks: web::Data<Watermarks>,
+    pool: web::Data<ProcessingPool>,
+) -> (Processed, bool) {
+    // Одинаковые одновременные запросы по URL скачиваются и кодируются один раз.
+    // Ключ - нормализованный URL, остальные параметры и выбранный формат.
+    let key = coalesce::key(&[
+        &fetch::normalize_url(&url),
+        &format!("{params:?}"),
+        &format!("{requested_format:?}"),
+    ]);
+    let fallback_url = params.fallback_url.take();
+    let redact_urls = config.log_redact_urls;
+    let url_inflight = inflight.clone();
+    let fetch_and_process = move |url: String, params: ResizeParams| {
+        let (client, config, failures) = (client.clone(), config.clone(), failures.clone());
+        let (watermarks, pool, inflight) = (watermarks.clone(), pool.clone(), inflight.clone());
+        async move {
+            if let Some(err) = failures.get(&url) {
+                let log_url = access_log::redact_url(&url, config.log_redact_urls);
+                tracing::debug!(url = %log_url, "source failed recently, not fetching again");
+                return Err(err);
+            }
+            let fetch_started = Instant::now();
+            let source = fetch_source(&client, &config, &url)
+                .await
+                .inspect(|_| failures.record_success(&url))
+                .inspect_err(|err| failures.record(&url, err))?;
+            let fetch = fetch_started.elapsed();
+            // Одинаковые байты с разных URL (зеркала, CDN) обрабатываются один раз:
+            // второй запрос с тем же содержимым и параметрами ждёт уже идущую обработку
+            let content_key = coalesce::key(&[
+                "content",
+                &format!("{:x}", Sha1::digest(&source.bytes)),
+                &format!(
+                    "{:?}",
+                    ResizeParams {
+                        url: None,
+                        ..params.clone()
+                    }
+                ),
+                &format!("{requested_format:?}"),
+            ]);
+            let process = async move {
+                process_blocking(
+                    source.bytes,
+                    source.format_hint,
+                    params,
+                    requested_format,
+                    watermarks,
+                    pool,
+                    &config,
+                )
+                .await
+            };
+            let (output, shared) = inflight.into_inner().run(content_key, process).await;
+            if shared {
+                tracing::debug!("same source bytes already processing, sharing the result");
+            }
+            let output = output?;
+            Ok(Output {
+                last_modified: source.last_modified,
+                timings: Timings {
+                    fetch: Some(fetch),
+                    ..output.timings
+                },
+                ..output
+            })
+        }
+    };
+    let work = async move {
+        let Some(fallback_url) = fallback_url else {
+            return fetch_and_process(url, params).await;
+        };
+        // Запасное изображение проходит те же проверки и обработку, что и основное.
+        // Ошибки запроса (неверный URL, запрещённый хост) его не включают.
+        match fetch_and_process(url.clone(), params.clone()).await {
+            Err(
+                err @ (AppError::FetchFailed
+                | AppError::FetchTimeout
+                | AppError::UpstreamUnavailable(_)
+                | AppError::SourceNotFound
+                | AppError::DecodeFailed(_)
+                | AppError::UnsupportedColorSpace(_)
+                | AppError::TooLarge),
+            ) => {
+                let log_url = access_log::redact_url(&url, redact_urls);
+                tracing::info!(url = %log_url, error = %err, "serving fallback image");
+                match fetch_and_process(fallback_url, params).await {
+                    Ok(output) => Ok(Output {
+                        fallback: true,
+                        ..output
+                    }),
+                    Err(_) => Err(err),
+                }
+            }
+            result => result,
+        }
+    };
+    let (output, coalesced) = url_inflight.into_inner().run(key, work).await;
+    tracing::Span::current().record("coalesced", coalesced);
+    (output, coalesced)
+}
+
+// Каждый аргумент - отдельный экстрактор actix
+#[allow(clippy::too_many_arguments)]
+async fn resize_image(
+    query: web::Query<ResizeParams>,
+    req: HttpRequest,
+    accept: Option<web::Header<Accept>>,
+    config: web::Data<Config>,
+    client: web::Data<reqwest::Client>,
+    inflight: web::Data<Inflight<Processed>>,
+    failures: web::Data<FailedFetches>,
+    watermarks: web::Data<Watermarks>,
+    presets: web::Data<Presets>,
+    pool: web::Data<ProcessingPool>,
+    payload: Option<Multipart>,
+) -> Result<HttpResponse, AppError> {
+    counter!(monitoring::REQUESTS_TOTAL).increment(1);
+    let mut params = query.into_inner();
+    let requested_format = prepare(
+        &mut params,
+        accept,
+        save_data(&req, &config),
+        &config,
+        &watermarks,
+        &presets,
+    )?;
+    // Формат мог прийти из пресета, тогда от Accept ответ не зависит, если не strict_accept
+    let vary_accept = params.format.is_none() || params.strict_accept == Some(true);
+    let max_age = cache_max_age(&params, &config);
+
+    let output = if let Some(url) = params.url.take() {
+        let (output, coalesced) = process_url(
+            url,
+            params,
+            requested_format,
+            config.clone(),
+            client,
+            inflight,
+            failures,
+            watermarks,
+            pool,
+        )
+        .await;
+        if coalesced {
+            req.extensions_mut().insert(CacheStatus::Coalesced);
+        }
+        output?
+    } else {
+        // Иначе ожидаем multipart загрузку
+        let img_data = read_upload(payload).await?;
+        process_blocking(
+            img_data,
+            None,
+            params,
+            requested_format,
+            watermarks,
+            pool,
+            &config,
+        )
+        .await?
+    };
+
+    Ok(respond(&req, output, vary_accept, max_age, &config))
+}
+
+// GET /resize/<опции>/<url> и то же под /optimize/, разбор пути в path_params.rs;
+// остальные параметры можно передать строкой запроса. Дальше запрос
+// обрабатывается как обычный /resize.
+// Каждый аргумент - отдельный экстрактор actix
+#[allow(clippy::too_many_arguments)]
+async fn resize_by_path(
+    query: web::Query<ResizeParams>,
+    req: HttpRequest,
+    accept: Option<web::Header<Accept>>,
+    config: web::Data<Config>,
+    client: web::Data<reqwest::Client>,
+    inflight: web::Data<Inflight<Processed>>,
+    failures: web::Data<FailedFetches>,
+    watermarks: web::Data<Watermarks>,
+    presets: web::Data<Presets>,
+    pool: web::Data<ProcessingPool>,
+) -> Result<HttpResponse, AppError> {
+    let mut params = query.into_inner();
+    // Сырой путь: адрес исходника раскодируется один раз, в path_params
+    let path = path_params::options(req.path()).unwrap_or_default();
+    path_params::apply(path, &mut params)?;
+    resize_image(
+        web::Query(params),
+        req,
+        accept,
+        config,
+        client,
+        inflight,
+        failures,
+        watermarks,
+        presets,
+        pool,
+        None,
+    )
+    .await
+}
+
+// Изображение целиком в теле запроса, параметры в строке запроса.
+// Параметр `url` здесь не используется.
+// Каждый аргумент - отдельный экстрактор actix
+#[allow(clippy::too_many_arguments)]
+async fn optimize_image(
+    query: web::Query<ResizeParams>,
+    req: HttpRequest,
+    accept: Option<web::Header<Accept>>,
+    config: web::Data<Config>,
+    watermarks: web::Data<Watermarks>,
+    presets: web::Data<Presets>,
+    pool: web::Data<ProcessingPool>,
+    payload: web::Payload,
+) -> Result<HttpResponse, AppError> {
+    counter!(monitoring::REQUESTS_TOTAL).increment(1);
+    let mut params = query.into_inner();
+    let requested_format = prepare(
+        &mut params,
+        accept,
+        save_data(&req, &config),
+        &config,
+        &watermarks,
+        &presets,
+    )?;
+    // Формат мог прийти из пресета, тогда от Accept ответ не зависит, если не strict_accept
+    let vary_accept = params.format.is_none() || params.strict_accept == Some(true);
+    let max_age = cache_max_age(&params, &config);
+
+    let img_data = read_raw_body(payload, config.max_upload_bytes).await?;
+    let output = process_blocking(
+        img_data,
+        None,
+        params,
+        requested_format,
+        watermarks,
+        pool,
+        &config,
+    )
+    .await?;
+
+    Ok(respond(&req, output, vary_accept, max_age, &config))
+}
+
+// Ждёт SIGTERM или SIGINT
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// Файл настроек из `--config <путь>` или `--config=<путь>`, иначе из CONFIG_FILE
+fn config_file_arg() -> Option<std::path::PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(Into::into);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(path.into());
+        }
+    }
+    std::env::var_os("CONFIG_FILE")
+        .filter(|path| !path.is_empty())
+        .map(Into::into)
+}
+
+// Маршруты для клиентов
+fn public_routes(cfg: &mut web::ServiceConfig) {
+    // Лимит запросов только на маршрутах, которые обрабатывают изображения
+    cfg.service(
+        web::resource("/resize")
+            .wrap(from_fn(concurrency::limit))
+            .wrap(from_fn(signing::verify))
+            .wrap(from_fn(ratelimit::limit))
+            .route(web::post().to(resize_image))
+            .route(web::get().to(resize_image)) // поддержка GET для URL
+            // HEAD обрабатывает изображение так же, как GET (кэша нет), и отдаёт
+            // те же заголовки без тела; одновременный GET дождётся той же обработки
+            .route(web::head().to(resize_image)),
+    )
+    .service(
+        web::resource("/resize/{options:.*}")
+            .wrap(from_fn(concurrency::limit))
+            .wrap(from_fn(signing::verify))
+            .wrap(from_fn(ratelimit::limit))
+            .route(web::get().to(resize_by_path))
+            .route(web::head().to(resize_by_path)),
+    )
+    .service(
+        web::resource("/optimize")
+            .wrap(from_fn(concurrency::limit))
+            .wrap(from_fn(signing::verify))
+            .wrap(from_fn(ratelimit::limit))
+            .route(web::post().to(optimize_image)),
+    )
+    .service(
+        web::resource("/optimize/batch")
+            // Место MAX_CONCURRENCY занимает каждый элемент, см. batch.rs
+            .wrap(from_fn(ratelimit::limit))
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(|err, _| AppError::InvalidBody(err.to_string()).into()),
+            )
+            // Подпись проверяется у каждого элемента, см. batch.rs
+            .route(web::post().to(batch::optimize_batch)),
+    )
+    .service(
+        // После /optimize/batch: иначе тот достался бы этому маршруту
+        web::resource("/optimize/{options:.*}")
+            .wrap(from_fn(concurrency::limit))
+            .wrap(from_fn(signing::verify))
+            .wrap(from_fn(ratelimit::limit))
+            .route(web::get().to(resize_by_path))
+            .route(web::head().to(resize_by_path)),
+    )
+    .service(
+        web::resource("/analyze")
+            .wrap(from_fn(concurrency::limit))
+            .wrap(from_fn(signing::verify))
+            .wrap(from_fn(ratelimit::limit))
+            .route(web::get().to(analyze::analyze)),
+    )
+    .service(
+        web::resource("/compare")
+            .wrap(from_fn(concurrency::limit))
+            .wrap(from_fn(signing::verify))
+            .wrap(from_fn(ratelimit::limit))
+            .route(web::get().to(compare::compare)),
+    )
+    .service(
+        web::resource("/srcset")
+            .wrap(from_fn(signing::verify))
+            .wrap(from_fn(ratelimit::limit))
+            .route(web::get().to(srcset::srcset)),
+    );
+}
+
+// Служебные маршруты: на ADMIN_LISTEN_ADDR, если он задан, иначе рядом с остальными
+fn admin_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/health", web::get().to(health::health))
+        .route("/ready", web::get().to(health::ready))
+        .route("/metrics", web::get().to(monitoring::metrics));
+}
+
+// Middleware обоих серверов. Сжатие снаружи всех остальных: журнал запросов
+// видит исходный размер ответа.
+fn with_middleware<T, B>(
+    app: App<T>,
+    health: web::Data<Health>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+>
+where
+    T: ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<B>,
+            Error = actix_web::Error,
+            InitError = (),
+        > + 'static,
+    B: MessageBody + 'static,
+{
+    app.wrap(from_fn(logging::trace))
+        .wrap(from_fn(access_log::log))
+        .wrap_fn(move |req, srv| {
+            let guard = Health::track(&health);
+            let response = srv.call(req);
+            async move {
+                let response = response.await;
+                guard.finish();
+                response
+            }
+        })
+        .wrap(from_fn(compress::skip_small))
+        .wrap(Compress::default())
+        .wrap(from_fn(compress::strip_identity))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
-        App::new()
-            .route("/resize", web::post().to(resize_image))
-            .route("/resize", web::get().to(resize_image)) // поддержка GET для URL
+    logging::init();
+    let config_file = config_file_arg();
+    let config = Config::load(config_file.as_deref()).unwrap_or_else(|errors| {
+        for err in errors {
+            tracing::error!("configuration error: {err}");
+        }
+        std::process::exit(1);
+    });
+    if let Some(path) = &config_file {
+        tracing::info!("config file: {}", path.display());
+    }
+    config.log();
+    // Сломанный кодировщик должен остановить запуск, а не первый запрос.
+    // С --selftest сервер только проверяет кодировщики и завершается.
+    if !selftest::run(&config) {
+        std::process::exit(1);
+    }
+    if std::env::args().skip(1).any(|arg| arg == "--selftest") {
+        return Ok(());
+    }
+    let client = web::Data::new(fetch::build_client(&config));
+    let config = web::Data::new(config);
+    let health = web::Data::new(Health::new());
+    let app_health = health.clone();
+    let metrics = web::Data::new(monitoring::install());
+    let inflight = web::Data::new(Inflight::<Processed>::new());
+    let failures = web::Data::new(FailedFetches::new(
+        Duration::from_secs(config.negative_cache_ttl_seconds),
+        CircuitBreaker::new(&config),
+    ));
+    let limiter = web::Data::new(RateLimiter::new(&config));
+    let concurrency = web::Data::new(ConcurrencyLimit::new(&config));
+    let watermarks = web::Data::new(Watermarks::load(config.watermark_dir.as_deref()));
+    let presets = web::Data::from(config.presets.clone());
+    let pool = web::Data::new(ProcessingPool::new(
+        config.processing_threads,
+        config.max_decoded_bytes,
+    ));
+    let shutdown_timeout = config.shutdown_grace_seconds;
+    let listen_addr = config.listen_addr;
+    let admin_addr = config.admin_listen_addr;
+
+    // Служебный сервер на отдельном порту: без CORS, лимитов и журнала запросов
+    let admin = admin_addr.map(|addr| {
+        let (config, health, metrics, failures, pool) = (
+            config.clone(),
+            health.clone(),
+            metrics.clone(),
+            failures.clone(),
+            pool.clone(),
+        );
+        HttpServer::new(move || {
+            let app = App::new()
+                .app_data(config.clone())
+                .app_data(health.clone())
+                .app_data(metrics.clone())
+                .app_data(failures.clone())
+                .app_data(pool.clone());
+            with_middleware(app, health.clone()).configure(admin_routes)
+        })
+        .workers(1)
+        .bind(addr)
+        .unwrap_or_else(|err| {
+            tracing::error!("failed to bind admin address {addr}: {err}");
+            std::process::exit(1);
+        })
+        .shutdown_timeout(shutdown_timeout)
+        .disable_signals()
+        .run()
+    });
+
+    let server = HttpServer::new(move || {
+        let app = App::new()
+            .app_data(config.clone())
+            .app_data(client.clone())
+            .app_data(app_health.clone())
+            .app_data(metrics.clone())
+            .app_data(inflight.clone())
+            .app_data(failures.clone())
+            .app_data(limiter.clone())
+            .app_data(concurrency.clone())
+            .app_data(watermarks.clone())
+            .app_data(presets.clone())
+            .app_data(pool.clone())
+            .wrap(from_fn(cors::handle));
+        with_middleware(app, app_health.clone())
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|err, _| AppError::InvalidQuery(err.to_string()).into()),
+            )
+            .configure(public_routes)
+            // Без ADMIN_LISTEN_ADDR служебные маршруты на том же порту
+            .configure(|cfg| {
+                if admin_addr.is_none() {
+                    admin_routes(cfg)
+                }
+            })
     })
-    .bind("127.0.0.1:3001")?
-    .run()
-    .await
+    .bind(listen_addr)
+    .unwrap_or_else(|err| {
+        tracing::error!("failed to bind {listen_addr}: {err}");
+        std::process::exit(1);
+    })
+    .shutdown_timeout(shutdown_timeout)
+    .disable_signals()
+    .run();
+
+    let handle = server.handle();
+    let admin_handle = admin.as_ref().map(|admin| admin.handle());
+    let mut server = actix_web::rt::spawn(server);
+    let mut admin = admin.map(actix_web::rt::spawn);
+    health.set_ready(true);
+
+    let admin_stopped = async {
+        match &mut admin {
+            Some(admin) => admin.await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        result = &mut server => return result.expect("server task panicked"),
+        result = admin_stopped => return result.expect("admin server task panicked"),
+        _ = wait_for_signal() => {}
+    }
+    // Снимаем готовность и ждём текущие запросы не дольше SHUTDOWN_GRACE_SECONDS
+    health.set_ready(false);
+    let draining = health.inflight();
+    let dropped_before = health.dropped();
+    tracing::info!("shutdown signal received, draining {draining} in-flight requests");
+    tokio::join!(handle.stop(true), async {
+        if let Some(admin_handle) = admin_handle {
+            admin_handle.stop(true).await;
+        }
+    });
+    let dropped = health.dropped() - dropped_before;
+    tracing::info!(
+        "shutdown complete: {} requests drained, {dropped} dropped",
+        draining.saturating_sub(dropped)
+    );
+    if let Some(admin) = admin {
+        admin.await.expect("admin server task panicked")?;
+    }
+    server.await.expect("server task panicked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn range(headers: &[(&str, &str)], len: u64) -> Option<(u64, u64)> {
+        let mut req = TestRequest::default();
+        for &header in headers {
+            req = req.insert_header(header);
+        }
+        byte_range(
+            &req.to_http_request(),
+            &EntityTag::new_strong("abc".to_string()),
+            None,
+            len,
+        )
+    }
+
+    // Множители дискретизации компонент из SOF0/SOF2: (горизонтальный, вертикальный)
+    fn sampling_factors(jpeg: &[u8]) -> Vec<(u8, u8)> {
+        let mut pos = 2;
+        while pos + 4 <= jpeg.len() {
+            let marker = jpeg[pos + 1];
+            let len = usize::from(u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]));
+            if marker == 0xc0 || marker == 0xc2 {
+                let sof = &jpeg[pos + 4..pos + 2 + len];
+                return sof[6..]
+                    .chunks(3)
+                    .take(usize::from(sof[5]))
+                    .map(|component| (component[1] >> 4, component[1] & 0x0f))
+                    .collect();
+            }
+            pos += 2 + len;
+        }
+        panic!("no SOF marker");
+    }
+
+    fn encode_jpeg_with(options: JpegOptions) -> Vec<u8> {
+        let img = RgbaImage::from_fn(32, 32, |x, y| {
+            image::Rgba([x as u8 * 8, y as u8 * 8, 0, 255])
+        });
+        encode(
+            &DynamicImage::ImageRgba8(img),
+            image::ImageFormat::Jpeg,
+            80.0,
+            None,
+            options,
+            false,
+        )
+        .unwrap()
+        .0
+    }
+
+    #[test]
+    fn jpeg_subsampling_defaults_to_420() {
+        let jpeg = encode_jpeg_with(JpegOptions::default());
+        assert_eq!(sampling_factors(&jpeg), [(2, 2), (1, 1), (1, 1)]);
+        let progressive = encode_jpeg_with(JpegOptions {
+            progressive: true,
+            ..JpegOptions::default()
+        });
+        assert_eq!(sampling_factors(&progressive), [(2, 2), (1, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn jpeg_subsampling_from_param() {
+        for (value, luma) in [("444", (1, 1)), ("422", (2, 1)), ("420", (2, 2))] {
+            let jpeg = encode_jpeg_with(JpegOptions {
+                subsampling: parse_subsampling(value).unwrap(),
+                ..JpegOptions::default()
+            });
+            assert_eq!(sampling_factors(&jpeg), [luma, (1, 1), (1, 1)], "{value}");
+        }
+    }
+
+    #[test]
+    fn single_byte_range() {
+        assert_eq!(range(&[("range", "bytes=0-9")], 100), Some((0, 9)));
+        assert_eq!(range(&[("range", "bytes=90-")], 100), Some((90, 99)));
+        assert_eq!(range(&[("range", "bytes=-10")], 100), Some((90, 99)));
+        // Конец за пределами тела урезается до последнего байта
+        assert_eq!(range(&[("range", "bytes=50-500")], 100), Some((50, 99)));
+    }
+
+    #[test]
+    fn full_body_instead_of_range() {
+        assert_eq!(range(&[], 100), None);
+        assert_eq!(range(&[("range", "bytes=0-9,20-29")], 100), None);
+        assert_eq!(range(&[("range", "bytes=200-")], 100), None);
+        assert_eq!(range(&[("range", "bytes=9-0")], 100), None);
+        assert_eq!(range(&[("range", "items=0-9")], 100), None);
+    }
+
+    #[test]
+    fn if_range_needs_the_same_strong_etag() {
+        let matching = [("range", "bytes=0-9"), ("if-range", "\"abc\"")];
+        assert_eq!(range(&matching, 100), Some((0, 9)));
+        let other = [("range", "bytes=0-9"), ("if-range", "\"def\"")];
+        assert_eq!(range(&other, 100), None);
+        let weak = [("range", "bytes=0-9"), ("if-range", "W/\"abc\"")];
+        assert_eq!(range(&weak, 100), None);
+    }
 }
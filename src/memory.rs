@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::AppError;
+
+// Байт на пиксель декодированного изображения: всё приводится к RGBA8
+const BYTES_PER_PIXEL: u64 = 4;
+// Через сколько секунд советуем повторить отклонённый запрос
+const RETRY_AFTER_SECONDS: u64 = 1;
+
+// Учёт памяти под декодированные изображения. Предел числа запросов не спасает
+// от нескольких огромных исходников сразу: каждый занимает ширина x высота x 4 байт
+// ещё до ресайза. Сверх `limit` новые изображения не декодируются, клиент получает 503.
+pub struct MemoryBudget {
+    // MAX_DECODED_BYTES; 0 - без ограничения, но учёт для /metrics ведётся
+    limit: u64,
+    used: AtomicU64,
+}
+
+// Зарезервированная память, освобождается при drop
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Self {
+        MemoryBudget {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    // Резервирует память под изображение `width` x `height` до декодирования.
+    // Изображение больше всего предела проходит, когда больше ничего не декодируется:
+    // иначе оно не прошло бы никогда, а его размер и так ограничен MAX_INPUT_PIXELS.
+    pub fn reserve(&self, dimensions: (u32, u32)) -> Result<Reservation<'_>, AppError> {
+        self.reserve_frames(dimensions, 1)
+    }
+
+    // То же для обработки, которая держит одновременно `frames` копий изображения
+    pub fn reserve_frames(
+        &self,
+        (width, height): (u32, u32),
+        frames: u64,
+    ) -> Result<Reservation<'_>, AppError> {
+        let bytes = u64::from(width) * u64::from(height) * BYTES_PER_PIXEL * frames;
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let fits = self.limit == 0 || used == 0 || used + bytes <= self.limit;
+                fits.then_some(used + bytes)
+            })
+            .map_err(|used| {
+                tracing::warn!(
+                    requested = bytes,
+                    in_flight = used,
+                    limit = self.limit,
+                    "decoded memory limit reached, rejecting image"
+                );
+                AppError::Overloaded(RETRY_AFTER_SECONDS)
+            })?;
+        Ok(Reservation {
+            budget: self,
+            bytes,
+        })
+    }
+
+    // Сколько байт сейчас занято декодированными изображениями
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,140 @@
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{ImageDecoder, ImageFormat};
+use std::borrow::Cow;
+use std::io::Cursor;
+
+// Метаданные при перекодировании. EXIF, XMP и прочие блоки не копируются никогда.
+// ICC-профиль при strip=false сохраняется так:
+//   JPEG - сегменты APP2 ICC_PROFILE
+//   PNG  - чанк iCCP
+//   WebP, AVIF - не сохраняется: кодировщики image и ravif не умеют его записывать
+
+// Полезная нагрузка одного сегмента APP2: 65535 минус длина (2) и заголовок (14)
+const ICC_SEGMENT_PAYLOAD: usize = 65519;
+const ICC_SEGMENT_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+
+// Значение тега EXIF Orientation (1-8), 1 если тега нет или он не читается
+pub fn exif_orientation(img_data: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(img_data))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+// Есть ли в исходнике блоки, которые перекодирование удалило бы: EXIF, XMP,
+// текстовые чанки PNG, комментарии JPEG. Поиск по байтам грубый, но ложное
+// срабатывание означает лишь, что исходник не будет отдан вместо результата.
+pub fn has_metadata(img_data: &[u8], format: Option<ImageFormat>) -> bool {
+    let contains = |needle: &[u8]| {
+        img_data
+            .windows(needle.len())
+            .any(|window| window == needle)
+    };
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(img_data))
+        .is_ok();
+    exif || contains(b"x:xmpmeta")
+        || match format {
+            Some(ImageFormat::Png) => [b"tEXt", b"zTXt", b"iTXt"]
+                .iter()
+                .any(|tag| contains(tag.as_slice())),
+            // Маркер COM; в сжатых данных за 0xFF всегда идёт 0x00 или RST
+            Some(ImageFormat::Jpeg) => contains(&[0xFF, 0xFE]),
+            _ => false,
+        }
+}
+
+// ICC-профиль исходника; читаются только заголовки, без декодирования пикселей
+pub fn read_icc_profile(img_data: &[u8], format: Option<ImageFormat>) -> Option<Vec<u8>> {
+    let cursor = Cursor::new(img_data);
+    match format? {
+        ImageFormat::Jpeg => JpegDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::Png => PngDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::WebP => WebPDecoder::new(cursor).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+// Байт transform из маркера Adobe (APP14) JPEG: 0 - CMYK, 1 - YCbCr, 2 - YCCK.
+// None - маркера нет. Просматриваются только сегменты до начала сжатых данных.
+pub fn jpeg_adobe_transform(jpeg: &[u8]) -> Option<u8> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    while pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF {
+        let marker = jpeg[pos + 1];
+        match marker {
+            // Заполняющий байт перед маркером
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // Маркеры без длины
+            0x01 | 0xD0..=0xD7 => {
+                pos += 2;
+                continue;
+            }
+            // SOS или EOI: дальше сегментов с метаданными нет
+            0xDA | 0xD9 => return None,
+            _ => {}
+        }
+        let length = usize::from(u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]));
+        let segment = jpeg.get(pos + 4..pos + 2 + length)?;
+        if marker == 0xEE && segment.len() >= 12 && segment.starts_with(b"Adobe") {
+            return Some(segment[11]);
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+// Вставляет профиль в готовый JPEG сразу после SOI и APP0 (JFIF должен идти первым)
+pub fn embed_icc_jpeg(jpeg: Vec<u8>, icc: &[u8]) -> Vec<u8> {
+    let segments: Vec<&[u8]> = icc.chunks(ICC_SEGMENT_PAYLOAD).collect();
+    if jpeg.len() < 4 || segments.is_empty() || segments.len() > usize::from(u8::MAX) {
+        return jpeg;
+    }
+    let mut insert_at = 2;
+    if jpeg[2..4] == [0xFF, 0xE0] && jpeg.len() >= 6 {
+        insert_at = 4 + usize::from(u16::from_be_bytes([jpeg[4], jpeg[5]]));
+    }
+
+    let mut out = Vec::with_capacity(jpeg.len() + icc.len() + segments.len() * 18);
+    out.extend_from_slice(&jpeg[..insert_at]);
+    for (index, segment) in segments.iter().enumerate() {
+        let length = (2 + ICC_SEGMENT_SIGNATURE.len() + 2 + segment.len()) as u16;
+        out.extend_from_slice(&[0xFF, 0xE2]);
+        out.extend_from_slice(&length.to_be_bytes());
+        out.extend_from_slice(ICC_SEGMENT_SIGNATURE);
+        out.push(index as u8 + 1);
+        out.push(segments.len() as u8);
+        out.extend_from_slice(segment);
+    }
+    out.extend_from_slice(&jpeg[insert_at..]);
+    out
+}
+
+// Кодирует RGBA8 в PNG с чанком iCCP, дописывая в `out`
+pub fn encode_png_with_icc(
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    icc: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<(), png::EncodingError> {
+    let mut info = png::Info::with_size(width, height);
+    info.color_type = png::ColorType::Rgba;
+    info.bit_depth = png::BitDepth::Eight;
+    info.icc_profile = Some(Cow::Borrowed(icc));
+
+    let mut writer = png::Encoder::with_info(out, info)?.write_header()?;
+    writer.write_image_data(rgba)?;
+    writer.finish()
+}
@@ -0,0 +1,60 @@
+use actix_web::{web, HttpResponse};
+use metrics::gauge;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::time::Duration;
+
+use crate::failures::FailedFetches;
+use crate::pool::ProcessingPool;
+
+// Имена метрик в формате Prometheus
+pub const REQUESTS_TOTAL: &str = "image_requests_total";
+pub const BYTES_SERVED_TOTAL: &str = "image_bytes_served_total";
+pub const DECODE_FAILURES_TOTAL: &str = "image_decode_failures_total";
+pub const FETCH_FAILURES_TOTAL: &str = "image_fetch_failures_total";
+pub const PROCESSING_SECONDS: &str = "image_processing_duration_seconds";
+pub const UPSTREAM_CIRCUITS: &str = "image_upstream_circuits";
+pub const DECODED_BYTES_IN_FLIGHT: &str = "image_decoded_bytes_in_flight";
+
+// Границы бакетов гистограммы времени обработки, секунды
+const PROCESSING_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+// Как часто сбрасывать накопленные значения гистограмм между опросами
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+// Устанавливает глобальный recorder и запускает его фоновое обслуживание
+pub fn install() -> PrometheusHandle {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(PROCESSING_SECONDS.to_string()),
+            PROCESSING_BUCKETS,
+        )
+        .expect("processing buckets are not empty")
+        .install_recorder()
+        .expect("failed to install metrics recorder");
+
+    let upkeep = handle.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(UPKEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    handle
+}
+
+pub async fn metrics(
+    handle: web::Data<PrometheusHandle>,
+    failures: web::Data<FailedFetches>,
+    pool: web::Data<ProcessingPool>,
+) -> HttpResponse {
+    // Состояние предохранителя зависит от времени, поэтому снимается при опросе
+    let circuits = failures.circuit_counts();
+    gauge!(UPSTREAM_CIRCUITS, "state" => "open").set(circuits.open as f64);
+    gauge!(UPSTREAM_CIRCUITS, "state" => "half_open").set(circuits.half_open as f64);
+    gauge!(DECODED_BYTES_IN_FLIGHT).set(pool.memory().used() as f64);
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(handle.render())
+}
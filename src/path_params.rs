@@ -0,0 +1,171 @@
+use percent_encoding::percent_decode_str;
+
+use crate::error::AppError;
+use crate::quality::parse_quality;
+use crate::{parse_fit, parse_output_format, parse_size, ResizeParams, BEST_FORMAT};
+
+// Параметры из пути вида `/resize/800x600/q80/webp/<url>` (или `/optimize/...`): по одной опции
+// в сегменте, затем адрес исходника. Опции:
+//   300, 300x200, x200          - как `size`
+//   q80, q72.5, qauto           - quality
+//   webp, jpeg, png, avif, best - format
+//   fill, contain, cover        - fit
+// Адрес лучше кодировать целиком (https%3A%2F%2Fexample.com%2Fa.png): так
+// строка запроса исходника не смешается со своей. Незакодированный адрес
+// тоже принимается. Значения из пути заменяют одноимённые из строки запроса.
+// Маршруты с опциями в пути; /optimize/batch к ним не относится
+const PREFIXES: [&str; 2] = ["/resize/", "/optimize/"];
+
+// Опции и адрес из пути запроса; None - путь не из этих маршрутов
+pub fn options(path: &str) -> Option<&str> {
+    if path == "/optimize/batch" {
+        return None;
+    }
+    PREFIXES.iter().find_map(|prefix| path.strip_prefix(prefix))
+}
+
+pub fn apply(path: &str, params: &mut ResizeParams) -> Result<(), AppError> {
+    let mut rest = path;
+    let url = loop {
+        let (segment, tail) = rest.split_once('/').unwrap_or((rest, ""));
+        let segment = decode(segment)?;
+        // В опциях двоеточия не бывает, а схема адреса им заканчивается
+        if segment.contains(':') {
+            break rest;
+        }
+        if !segment.is_empty() {
+            apply_option(&segment, params)?;
+        }
+        if tail.is_empty() {
+            return Err(AppError::NoImage);
+        }
+        rest = tail;
+    };
+
+    // Адрес с `://` или data: пришёл незакодированным, иначе раскодируем сегмент
+    let url = if url.contains("://") || url.starts_with("data:") {
+        url.to_string()
+    } else {
+        decode(url)?
+    };
+    params.url = Some(restore_scheme_slashes(url));
+    Ok(())
+}
+
+fn apply_option(segment: &str, params: &mut ResizeParams) -> Result<(), AppError> {
+    if let Some((width, height)) = parse_size(segment) {
+        params.size = None;
+        params.width = width;
+        params.height = height;
+    } else if let Some(quality) = segment
+        .strip_prefix('q')
+        .filter(|quality| parse_quality(quality).is_some())
+    {
+        params.quality = Some(quality.to_string());
+    } else if parse_output_format(segment).is_some() || segment == BEST_FORMAT {
+        params.format = Some(segment.to_string());
+    } else if parse_fit(segment).is_some() {
+        params.fit = Some(segment.to_string());
+    } else {
+        return Err(AppError::InvalidParam("Unknown option in path"));
+    }
+    Ok(())
+}
+
+fn decode(value: &str) -> Result<String, AppError> {
+    percent_decode_str(value)
+        .decode_utf8()
+        .map(|value| value.into_owned())
+        .map_err(|_| AppError::InvalidUrl)
+}
+
+// Прокси и балансировщики иногда схлопывают `//` в пути: https:/host -> https://host
+fn restore_scheme_slashes(url: String) -> String {
+    for scheme in ["http:/", "https:/"] {
+        if let Some(rest) = url.strip_prefix(scheme) {
+            if !rest.starts_with('/') {
+                return format!("{scheme}/{rest}");
+            }
+        }
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(path: &str) -> Result<ResizeParams, AppError> {
+        let mut params = ResizeParams::default();
+        apply(path, &mut params).map(|()| params)
+    }
+
+    #[test]
+    fn options_and_encoded_url() {
+        let params =
+            parse("800x600/q72.5/webp/cover/https%3A%2F%2Fexample.com%2Fa.png%3Fv%3D1").unwrap();
+        assert_eq!((params.width, params.height), (Some(800), Some(600)));
+        assert_eq!(params.quality.as_deref(), Some("72.5"));
+        assert_eq!(params.format.as_deref(), Some("webp"));
+        assert_eq!(params.fit.as_deref(), Some("cover"));
+        assert_eq!(params.url.as_deref(), Some("https://example.com/a.png?v=1"));
+
+        let params = parse("x200/qauto/best/https%3A%2F%2Fexample.com%2Fa.png").unwrap();
+        assert_eq!((params.width, params.height), (None, Some(200)));
+        assert_eq!(params.quality.as_deref(), Some("auto"));
+        assert_eq!(params.format.as_deref(), Some(BEST_FORMAT));
+    }
+
+    #[test]
+    fn unencoded_url() {
+        let params = parse("300/https://example.com/img/a%20b.png").unwrap();
+        assert_eq!(params.width, Some(300));
+        // Незакодированный адрес берётся как есть, без раскодирования
+        assert_eq!(
+            params.url.as_deref(),
+            Some("https://example.com/img/a%20b.png")
+        );
+        // Схлопнутые прокси слеши после схемы восстанавливаются
+        let params = parse("300/https:/example.com/a.png").unwrap();
+        assert_eq!(params.url.as_deref(), Some("https://example.com/a.png"));
+        // Пустые сегменты пропускаются
+        let params = parse("300//png/https%3A%2F%2Fexample.com%2Fa.png").unwrap();
+        assert_eq!(params.format.as_deref(), Some("png"));
+    }
+
+    #[test]
+    fn path_overrides_query() {
+        let mut params = ResizeParams {
+            quality: Some("50".to_string()),
+            format: Some("png".to_string()),
+            ..ResizeParams::default()
+        };
+        apply("q80/webp/https%3A%2F%2Fexample.com%2Fa.png", &mut params).unwrap();
+        assert_eq!(params.quality.as_deref(), Some("80"));
+        assert_eq!(params.format.as_deref(), Some("webp"));
+    }
+
+    #[test]
+    fn rejected_paths() {
+        assert!(matches!(parse("800x600/webp"), Err(AppError::NoImage)));
+        assert!(matches!(parse(""), Err(AppError::NoImage)));
+        assert!(matches!(
+            parse("huge/https%3A%2F%2Fexample.com%2Fa.png"),
+            Err(AppError::InvalidParam(_))
+        ));
+        assert!(matches!(
+            parse("qhigh/https%3A%2F%2Fexample.com%2Fa.png"),
+            Err(AppError::InvalidParam(_))
+        ));
+        assert!(matches!(parse("300/%FF%3A"), Err(AppError::InvalidUrl)));
+    }
+
+    #[test]
+    fn route_prefixes() {
+        assert_eq!(options("/resize/300/x"), Some("300/x"));
+        assert_eq!(options("/optimize/300/x"), Some("300/x"));
+        assert_eq!(options("/optimize/batch"), None);
+        assert_eq!(options("/resize"), None);
+        assert_eq!(options("/analyze"), None);
+    }
+}
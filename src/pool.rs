@@ -0,0 +1,65 @@
+use actix_web::error::BlockingError;
+use actix_web::web;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::memory::MemoryBudget;
+
+// Ограничивает число одновременных задач обработки изображений.
+// Blocking-пул tokio растёт до 512 потоков, и под нагрузкой CPU-задачи
+// начинают мешать друг другу. Задачи сверх `threads` ждут своей очереди,
+// а не порождают новые потоки. Память под декодированные изображения
+// ограничивается отдельно, см. memory.rs.
+pub struct ProcessingPool {
+    permits: Arc<Semaphore>,
+    memory: Arc<MemoryBudget>,
+}
+
+impl ProcessingPool {
+    pub fn new(threads: usize, max_decoded_bytes: u64) -> Self {
+        ProcessingPool {
+            permits: Arc::new(Semaphore::new(threads.max(1))),
+            memory: Arc::new(MemoryBudget::new(max_decoded_bytes)),
+        }
+    }
+
+    // Учёт памяти для задач, которые декодируют изображения
+    pub fn memory(&self) -> Arc<MemoryBudget> {
+        self.memory.clone()
+    }
+
+    // Ждёт свободного места. Ожидание в очереди не входит в PROCESSING_TIMEOUT_SECONDS:
+    // таймаут ставится на Slot::run, то есть только на саму обработку.
+    pub async fn acquire(&self) -> Slot {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("processing semaphore is never closed");
+        Slot { permit }
+    }
+}
+
+// Занятое место в пуле обработки
+pub struct Slot {
+    permit: OwnedSemaphorePermit,
+}
+
+impl Slot {
+    // Выполняет `work` в blocking-пуле. Разрешение живёт внутри задачи: если
+    // ожидающий её запрос отменят (например, по таймауту), поток остаётся занят
+    // до конца работы и место не освобождается раньше.
+    pub async fn run<F, R>(self, work: F) -> Result<R, BlockingError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let permit = self.permit;
+        web::block(move || {
+            let _permit = permit;
+            work()
+        })
+        .await
+    }
+}
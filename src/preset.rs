@@ -0,0 +1,112 @@
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::{parse_filter, parse_output_format, quality};
+
+// Именованный набор параметров. Явные параметры запроса важнее пресета.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    // Как параметр quality запроса: число, можно дробное, или "auto"
+    #[serde(default, deserialize_with = "quality_value")]
+    pub quality: Option<String>,
+    pub format: Option<String>,
+    pub filter: Option<String>,
+}
+
+// quality в файле можно писать числом или строкой
+fn quality_value<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Number(serde_json::Number),
+        Text(String),
+    }
+    Ok(
+        Option::<Value>::deserialize(deserializer)?.map(|value| match value {
+            Value::Number(number) => number.to_string(),
+            Value::Text(text) => text,
+        }),
+    )
+}
+
+// Встроенные пресеты; файл из PRESETS_FILE может их переопределить и дополнить
+fn builtin() -> HashMap<String, Preset> {
+    let preset = |quality: Option<&str>, format: &str, filter: Option<&str>| Preset {
+        quality: quality.map(str::to_string),
+        format: Some(format.to_string()),
+        filter: filter.map(str::to_string),
+    };
+    HashMap::from([
+        (
+            "thumbnail".to_string(),
+            preset(Some("60"), "webp", Some("lanczos3")),
+        ),
+        ("hero".to_string(), preset(Some("82"), "webp", None)),
+        ("lossless".to_string(), preset(None, "png", None)),
+    ])
+}
+
+pub struct Presets {
+    presets: HashMap<String, Preset>,
+}
+
+impl Default for Presets {
+    fn default() -> Self {
+        Presets { presets: builtin() }
+    }
+}
+
+impl Presets {
+    // Файл - JSON-объект {"имя": {"quality": 60, "format": "webp", "filter": "lanczos3"}}.
+    // Нечитаемый файл и неверные пресеты - ошибки конфигурации, как и прочие
+    // настройки: сервер не запускается, все ошибки выводятся разом.
+    pub fn load(path: Option<&Path>) -> Result<Self, Vec<String>> {
+        let Some(path) = path else {
+            return Ok(Presets::default());
+        };
+        let loaded: HashMap<String, Preset> = fs::read(path)
+            .map_err(|err| err.to_string())
+            .and_then(|data| serde_json::from_slice(&data).map_err(|err| err.to_string()))
+            .map_err(|err| {
+                vec![format!(
+                    "cannot load presets from {}: {err}",
+                    path.display()
+                )]
+            })?;
+        let mut errors = Vec::new();
+        let mut names: Vec<&String> = loaded.keys().collect();
+        names.sort();
+        for name in names {
+            let preset = &loaded[name];
+            if let Some(value) = preset.quality.as_deref() {
+                if quality::parse_quality(value).is_none() {
+                    errors.push(format!("preset {name}: invalid quality {value:?}"));
+                }
+            }
+            if let Some(value) = preset.format.as_deref() {
+                if parse_output_format(value).is_none() {
+                    errors.push(format!("preset {name}: unsupported format {value:?}"));
+                }
+            }
+            if let Some(value) = preset.filter.as_deref() {
+                if parse_filter(value).is_none() {
+                    errors.push(format!("preset {name}: unsupported filter {value:?}"));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        tracing::info!("loaded {} presets from {}", loaded.len(), path.display());
+        let mut presets = builtin();
+        presets.extend(loaded);
+        Ok(Presets { presets })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        self.presets.get(name)
+    }
+}
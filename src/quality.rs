@@ -0,0 +1,123 @@
+// Перевод quality из запроса в quality кодировщика. Шкалы у кодировщиков разные:
+// при одинаковом числе WebP и тем более AVIF выглядят иначе, чем JPEG.
+
+use image::DynamicImage;
+
+// Опорные точки кривой perceptual: quality по шкале JPEG (libjpeg) и то же
+// качество на глаз в шкале кодировщика. Между точками - линейно.
+// Ориентир - AVIF 60 и WebP 75 примерно как JPEG 80.
+const WEBP_CURVE: [(f32, f32); 5] = [
+    (1.0, 1.0),
+    (50.0, 45.0),
+    (80.0, 75.0),
+    (90.0, 86.0),
+    (100.0, 100.0),
+];
+const AVIF_CURVE: [(f32, f32); 5] = [
+    (1.0, 1.0),
+    (50.0, 35.0),
+    (80.0, 60.0),
+    (90.0, 72.0),
+    (100.0, 100.0),
+];
+
+// quality=auto: по шкале JPEG от AUTO_MIN для плоских изображений до AUTO_MAX
+// для детальных, затем как perceptual в шкалу формата вывода
+const AUTO_MIN: f32 = 55.0;
+const AUTO_MAX: f32 = 90.0;
+// Доля пикселей-границ, с которой изображение считается детальным
+const DETAILED_EDGE_DENSITY: f32 = 0.3;
+// Перепад яркости с соседями, начиная с которого пиксель считается границей
+const EDGE_THRESHOLD: i32 = 24;
+// Наибольшая сторона уменьшенной копии, по которой оценивается детализация
+const SAMPLE_SIZE: u32 = 256;
+
+// Значение параметра quality
+#[derive(Clone, Copy)]
+pub enum Quality {
+    Value(f32),
+    // Подобрать по детализации изображения, см. auto_quality
+    Auto,
+}
+
+// Число (можно дробное) или auto
+pub fn parse_quality(value: &str) -> Option<Quality> {
+    if value.eq_ignore_ascii_case("auto") {
+        return Some(Quality::Auto);
+    }
+    value
+        .parse()
+        .ok()
+        .filter(|quality: &f32| quality.is_finite())
+        .map(Quality::Value)
+}
+
+// Детализация изображения от 0 до 1: доля пикселей, яркость которых заметно
+// отличается от соседа справа или снизу. Плоская заливка - 0, шум - около 1.
+pub fn complexity(img: &DynamicImage) -> f32 {
+    let sample = if img.width().max(img.height()) > SAMPLE_SIZE {
+        img.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE).to_luma8()
+    } else {
+        img.to_luma8()
+    };
+    let (width, height) = sample.dimensions();
+    if width < 2 || height < 2 {
+        return 0.0;
+    }
+    let luma = |x: u32, y: u32| i32::from(sample.get_pixel(x, y).0[0]);
+    let mut edges = 0u32;
+    for y in 0..height - 1 {
+        for x in 0..width - 1 {
+            let center = luma(x, y);
+            let step = (center - luma(x + 1, y)).abs() + (center - luma(x, y + 1)).abs();
+            if step > EDGE_THRESHOLD {
+                edges += 1;
+            }
+        }
+    }
+    edges as f32 / ((width - 1) * (height - 1)) as f32
+}
+
+// quality кодировщика `format` для детализации `complexity` из complexity(), целое
+pub fn auto_quality(format: image::ImageFormat, complexity: f32) -> f32 {
+    let detail = (complexity / DETAILED_EDGE_DENSITY).clamp(0.0, 1.0);
+    QualityCurve::Perceptual
+        .encoder_quality(format, AUTO_MIN + (AUTO_MAX - AUTO_MIN) * detail)
+        .round()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum QualityCurve {
+    // quality передаётся кодировщику как есть
+    Linear,
+    // quality задаётся по шкале JPEG и переводится в шкалу формата вывода,
+    // чтобы при смене формата качество на глаз не менялось
+    Perceptual,
+}
+
+pub fn parse_curve(value: &str) -> Option<QualityCurve> {
+    match value {
+        "linear" => Some(QualityCurve::Linear),
+        "perceptual" => Some(QualityCurve::Perceptual),
+        _ => None,
+    }
+}
+
+impl QualityCurve {
+    // quality из запроса (1-100, можно дробное) в quality кодировщика `format`
+    pub fn encoder_quality(self, format: image::ImageFormat, quality: f32) -> f32 {
+        let quality = quality.clamp(1.0, 100.0);
+        let curve = match (self, format) {
+            (QualityCurve::Perceptual, image::ImageFormat::WebP) => &WEBP_CURVE,
+            (QualityCurve::Perceptual, image::ImageFormat::Avif) => &AVIF_CURVE,
+            _ => return quality,
+        };
+        curve
+            .windows(2)
+            .find(|points| quality <= points[1].0)
+            .map_or(quality, |points| {
+                let [(x0, y0), (x1, y1)] = [points[0], points[1]];
+                y0 + (quality - x0) * (y1 - y0) / (x1 - x0)
+            })
+    }
+}
@@ -0,0 +1,244 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::AppError;
+
+// Ведро токенов одного клиента
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Ограничение частоты запросов по IP клиента. Каждый клиент получает `burst`
+// запросов сразу и `rate` новых в секунду. Число отслеживаемых клиентов
+// ограничено `max_clients`, чтобы поток уникальных адресов не раздувал карту.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    max_clients: usize,
+    trust_forwarded_for: bool,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        RateLimiter {
+            rate: config.rate_limit_per_second,
+            burst: f64::from(config.rate_limit_burst.max(1)),
+            max_clients: config.rate_limit_max_clients.max(1),
+            trust_forwarded_for: config.trust_forwarded_for,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Лимит выключен, если RATE_LIMIT_PER_SECOND не больше нуля
+    fn enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<IpAddr, Bucket>> {
+        self.buckets.lock().unwrap_or_else(|poisoned| {
+            tracing::warn!("rate limiter mutex was poisoned, recovering");
+            self.buckets.clear_poison();
+            poisoned.into_inner()
+        })
+    }
+
+    // Списывает токен клиента. Если токенов нет, возвращает, через сколько появится следующий.
+    fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.lock();
+        if !buckets.contains_key(&ip) && buckets.len() >= self.max_clients {
+            self.evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    // Освобождает место в карте: сначала убирает вёдра, которые уже наполнились
+    // (такой клиент ничем не отличается от нового), а если таких нет -
+    // самое давно не обновлявшееся
+    fn evict(&self, buckets: &mut HashMap<IpAddr, Bucket>, now: Instant) {
+        let burst = self.burst;
+        let rate = self.rate;
+        buckets.retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+        });
+        if buckets.len() >= self.max_clients {
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.updated)
+                .map(|(ip, _)| *ip);
+            if let Some(ip) = oldest {
+                buckets.remove(&ip);
+            }
+        }
+    }
+
+    // Списывает токен клиента запроса. Без лимита или без адреса клиента - всегда Ok.
+    pub fn check(&self, req: &HttpRequest) -> Result<(), AppError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let Some(ip) = client_ip(req, self.trust_forwarded_for) else {
+            return Ok(());
+        };
+        self.acquire(ip).map_err(|wait| {
+            // Retry-After в целых секундах, округляем вверх
+            let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            AppError::RateLimited(seconds.max(1))
+        })
+    }
+}
+
+// IP клиента: за прокси - последний адрес из X-Forwarded-For (его добавил
+// ближайший прокси, остальные клиент мог подставить сам), иначе адрес сокета
+pub fn client_ip(req: &HttpRequest, trust_forwarded_for: bool) -> Option<IpAddr> {
+    if trust_forwarded_for {
+        let forwarded = req
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|value| value.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    req.peer_addr().map(|addr| addr.ip())
+}
+
+// Middleware для дорогих маршрутов: 429 с Retry-After, если клиент превысил лимит
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() {
+        limiter.check(req.request())?;
+    }
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn limiter(rate: f64, burst: u32, max_clients: usize) -> RateLimiter {
+        RateLimiter {
+            rate,
+            burst: f64::from(burst),
+            max_clients,
+            trust_forwarded_for: false,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([203, 0, 113, last])
+    }
+
+    // Сдвигает время последнего обновления ведра в прошлое
+    fn age(limiter: &RateLimiter, ip: IpAddr, by: Duration) {
+        let mut buckets = limiter.lock();
+        let bucket = buckets.get_mut(&ip).unwrap();
+        bucket.updated = bucket.updated.checked_sub(by).unwrap();
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let limiter = limiter(2.0, 3, 100);
+        for _ in 0..3 {
+            assert!(limiter.acquire(ip(1)).is_ok());
+        }
+        let wait = limiter.acquire(ip(1)).unwrap_err();
+        assert!(wait <= Duration::from_millis(500), "{wait:?}");
+        // Другой клиент не затронут
+        assert!(limiter.acquire(ip(2)).is_ok());
+
+        // За секунду при rate 2 появляются два токена
+        age(&limiter, ip(1), Duration::from_secs(1));
+        assert!(limiter.acquire(ip(1)).is_ok());
+        assert!(limiter.acquire(ip(1)).is_ok());
+        assert!(limiter.acquire(ip(1)).is_err());
+
+        // Токены не копятся сверх burst
+        age(&limiter, ip(1), Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(limiter.acquire(ip(1)).is_ok());
+        }
+        assert!(limiter.acquire(ip(1)).is_err());
+    }
+
+    #[test]
+    fn eviction_keeps_limited_clients() {
+        let limiter = limiter(1.0, 2, 2);
+        limiter.acquire(ip(1)).unwrap();
+        limiter.acquire(ip(1)).unwrap();
+        limiter.acquire(ip(2)).unwrap();
+        // Ведро ip(2) наполнилось: его можно забыть, ip(1) - нет
+        age(&limiter, ip(2), Duration::from_secs(5));
+        limiter.acquire(ip(3)).unwrap();
+        {
+            let buckets = limiter.lock();
+            assert!(buckets.contains_key(&ip(1)));
+            assert!(!buckets.contains_key(&ip(2)));
+            assert_eq!(buckets.len(), 2);
+        }
+        assert!(limiter.acquire(ip(1)).is_err());
+
+        // Наполненных нет: уходит самое давнее
+        age(&limiter, ip(3), Duration::from_millis(100));
+        limiter.acquire(ip(4)).unwrap();
+        let buckets = limiter.lock();
+        assert!(!buckets.contains_key(&ip(3)));
+        assert!(buckets.contains_key(&ip(1)) && buckets.contains_key(&ip(4)));
+    }
+
+    #[test]
+    fn check_reports_retry_after() {
+        let enabled = limiter(0.25, 1, 100);
+        let req = TestRequest::default()
+            .peer_addr("203.0.113.9:1234".parse().unwrap())
+            .to_http_request();
+        assert!(enabled.check(&req).is_ok());
+        // Следующий токен через 4 секунды
+        assert!(matches!(enabled.check(&req), Err(AppError::RateLimited(4))));
+
+        // RATE_LIMIT_PER_SECOND = 0 выключает лимит
+        let disabled = limiter(0.0, 1, 100);
+        for _ in 0..10 {
+            assert!(disabled.check(&req).is_ok());
+        }
+    }
+
+    #[test]
+    fn forwarded_for_only_when_trusted() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("x-forwarded-for", "1.2.3.4, 203.0.113.7"))
+            .to_http_request();
+        assert_eq!(client_ip(&req, true), Some(ip(7)));
+        assert_eq!(client_ip(&req, false), "10.0.0.1".parse().ok());
+    }
+}
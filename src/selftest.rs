@@ -0,0 +1,94 @@
+use image::{ImageFormat, Rgba, RgbaImage};
+use std::io::Cursor;
+
+use crate::config::Config;
+use crate::memory::MemoryBudget;
+use crate::watermark::Watermarks;
+use crate::{process_image, ResizeParams};
+
+// Сторона синтетического исходника и результата
+const SOURCE_SIZE: u32 = 16;
+const OUTPUT_SIZE: u32 = 8;
+// Форматы вывода, которые должен уметь кодировать сервер
+const FORMATS: [ImageFormat; 4] = [
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::WebP,
+    ImageFormat::Avif,
+];
+
+// Проверка сборки до приёма запросов: градиент с прозрачностью проходит через
+// process_image в каждый формат, результат декодируется обратно. AVIF декодировать
+// здесь нечем, у него проверяется заголовок контейнера. false - хотя бы один
+// формат не работает, причина уже в логе.
+pub fn run(config: &Config) -> bool {
+    let source = RgbaImage::from_fn(SOURCE_SIZE, SOURCE_SIZE, |x, y| {
+        Rgba([
+            (x * 16) as u8,
+            (y * 16) as u8,
+            128,
+            if x < 4 { 0 } else { 255 },
+        ])
+    });
+    let mut png = Vec::new();
+    if let Err(err) = source.write_to(&mut Cursor::new(&mut png), ImageFormat::Png) {
+        tracing::error!("self-test: cannot encode source image: {err}");
+        return false;
+    }
+
+    let watermarks = Watermarks::load(None);
+    let mut passed = true;
+    for format in FORMATS {
+        // Явный format, иначе JPEG с прозрачностью заменяется на PNG
+        let params = ResizeParams {
+            width: Some(OUTPUT_SIZE),
+            height: Some(OUTPUT_SIZE),
+            format: format.extensions_str().first().map(|ext| ext.to_string()),
+            ..ResizeParams::default()
+        };
+        let result = process_image(
+            png.clone(),
+            &params,
+            Some(format),
+            &watermarks,
+            // Предел MAX_INPUT_PIXELS к проверке кодировщиков не относится
+            u64::MAX,
+            OUTPUT_SIZE,
+            Some(ImageFormat::Png),
+            config.default_quality,
+            &MemoryBudget::new(0),
+        )
+        .map_err(|err| err.to_string())
+        .and_then(|output| check(&output.bytes, output.content_type, format));
+        if let Err(err) = result {
+            tracing::error!("self-test failed for {format:?}: {err}");
+            passed = false;
+        }
+    }
+    if passed {
+        tracing::info!("self-test passed: {FORMATS:?}");
+    }
+    passed
+}
+
+fn check(bytes: &[u8], content_type: &str, format: ImageFormat) -> Result<(), String> {
+    if content_type != format.to_mime_type() {
+        return Err(format!("encoded as {content_type}"));
+    }
+    if format == ImageFormat::Avif {
+        return match bytes.get(4..12) {
+            Some(b"ftypavif") => Ok(()),
+            _ => Err("output is not an AVIF container".to_string()),
+        };
+    }
+    let decoded =
+        image::load_from_memory_with_format(bytes, format).map_err(|err| err.to_string())?;
+    if (decoded.width(), decoded.height()) != (OUTPUT_SIZE, OUTPUT_SIZE) {
+        return Err(format!(
+            "decoded as {}x{}",
+            decoded.width(),
+            decoded.height()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::Config;
+use crate::error::AppError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Имя параметра с подписью
+const SIG_PARAM: &str = "sig";
+
+// Подпись запроса: hex(HMAC-SHA256(SIGNING_SECRET, "<path>?<query>")), где query -
+// все параметры, кроме `sig`, отсортированные по имени (затем по значению)
+// и закодированные как application/x-www-form-urlencoded. Путь входит в подпись,
+// чтобы подпись для одного маршрута не подходила к другому.
+pub fn sign(secret: &str, path: &str, params: &[(String, String)]) -> String {
+    hex(&mac(secret, path, params).finalize().into_bytes())
+}
+
+fn mac(secret: &str, path: &str, params: &[(String, String)]) -> HmacSha256 {
+    let mut sorted: Vec<&(String, String)> = params
+        .iter()
+        .filter(|(name, _)| name != SIG_PARAM)
+        .collect();
+    sorted.sort();
+    let query = serde_urlencoded::to_string(sorted).unwrap_or_default();
+
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key");
+    mac.update(path.as_bytes());
+    mac.update(b"?");
+    mac.update(query.as_bytes());
+    mac
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Есть ли среди параметров верный `sig` для пути
+pub fn is_valid(secret: &str, path: &str, params: &[(String, String)]) -> bool {
+    let signature = params
+        .iter()
+        .find(|(name, _)| name == SIG_PARAM)
+        .and_then(|(_, value)| unhex(value));
+    // Сравнение в постоянное время внутри verify_slice
+    signature.is_some_and(|signature| mac(secret, path, params).verify_slice(&signature).is_ok())
+}
+
+// Middleware: при заданном SIGNING_SECRET пропускает только запросы с верной
+// подписью, иначе 403 до любой работы. Без секрета проверка выключена.
+pub async fn verify(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let secret = req
+        .app_data::<web::Data<Config>>()
+        .and_then(|config| config.signing_secret.clone());
+    if let Some(secret) = secret {
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+        if !is_valid(&secret, req.path(), &params) {
+            return Err(AppError::InvalidSignature.into());
+        }
+    }
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn signed(path: &str, pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        let mut params = params(pairs);
+        params.push((SIG_PARAM.to_string(), sign("secret", path, &params)));
+        params
+    }
+
+    #[test]
+    fn accepts_own_signature_in_any_order() {
+        let mut params = signed(
+            "/resize",
+            &[("url", "https://a.test/x.png"), ("width", "100")],
+        );
+        assert!(is_valid("secret", "/resize", &params));
+        params.reverse();
+        assert!(is_valid("secret", "/resize", &params));
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let params = signed(
+            "/resize",
+            &[("url", "https://a.test/x.png"), ("width", "100")],
+        );
+        assert!(!is_valid("other", "/resize", &params));
+        assert!(!is_valid("secret", "/analyze", &params));
+
+        let mut changed = params.clone();
+        changed[1].1 = "1000".to_string();
+        assert!(!is_valid("secret", "/resize", &changed));
+
+        let mut extra = params.clone();
+        extra.push(("height".to_string(), "10".to_string()));
+        assert!(!is_valid("secret", "/resize", &extra));
+    }
+
+    #[test]
+    fn rejects_missing_or_malformed_signature() {
+        let unsigned = params(&[("url", "https://a.test/x.png")]);
+        assert!(!is_valid("secret", "/resize", &unsigned));
+        for sig in ["", "abc", "zz", "ёё"] {
+            let mut params = unsigned.clone();
+            params.push((SIG_PARAM.to_string(), sig.to_string()));
+            assert!(!is_valid("secret", "/resize", &params), "{sig:?}");
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use image::{imageops, RgbaImage};
+
+// Наибольшая сторона уменьшенной копии, по которой оценивается детализация
+const SAMPLE_SIZE: u32 = 256;
+// Сколько положений окна перебирается вдоль свободной оси
+const STEPS: u32 = 32;
+
+// Для fit=cover и crop=smart: сдвигает область `cover_crop` вдоль оси, по которой
+// исходник шире цели, туда, где больше всего деталей - максимум энтропии яркости.
+// При равной оценке остаётся центр. Возвращает новые left и top.
+pub fn entropy_window(
+    img: &RgbaImage,
+    (left, top, width, height): (f64, f64, f64, f64),
+) -> (f64, f64) {
+    let (src_width, src_height) = img.dimensions();
+    let scale = (f64::from(SAMPLE_SIZE) / f64::from(src_width.max(src_height))).min(1.0);
+    let sample = imageops::thumbnail(
+        img,
+        ((f64::from(src_width) * scale).round() as u32).max(1),
+        ((f64::from(src_height) * scale).round() as u32).max(1),
+    );
+    let (sample_width, sample_height) = sample.dimensions();
+    let (scale_x, scale_y) = (
+        f64::from(sample_width) / f64::from(src_width),
+        f64::from(sample_height) / f64::from(src_height),
+    );
+    // Прозрачные пиксели считаются чёрными: они не добавляют деталей
+    let luma: Vec<u8> = sample
+        .pixels()
+        .map(|px| {
+            let [r, g, b, a] = px.0.map(u32::from);
+            ((r * 299 + g * 587 + b * 114) / 1000 * a / 255) as u8
+        })
+        .collect();
+
+    let window_width = ((width * scale_x).round() as u32).clamp(1, sample_width);
+    let window_height = ((height * scale_y).round() as u32).clamp(1, sample_height);
+    let (free_x, free_y) = (sample_width - window_width, sample_height - window_height);
+    let score = |x: u32, y: u32| entropy(&luma, sample_width, x, y, window_width, window_height);
+
+    let (mut best_x, mut best_y) = (free_x / 2, free_y / 2);
+    let mut best_score = score(best_x, best_y);
+    for step in 0..=STEPS {
+        let (x, y) = (free_x * step / STEPS, free_y * step / STEPS);
+        let candidate = score(x, y);
+        if candidate > best_score {
+            (best_x, best_y, best_score) = (x, y, candidate);
+        }
+    }
+    if (best_x, best_y) == (free_x / 2, free_y / 2) {
+        return (left, top);
+    }
+
+    let max_left = (f64::from(src_width) - width).max(0.0);
+    let max_top = (f64::from(src_height) - height).max(0.0);
+    (
+        (f64::from(best_x) / scale_x).clamp(0.0, max_left),
+        (f64::from(best_y) / scale_y).clamp(0.0, max_top),
+    )
+}
+
+// Энтропия Шеннона гистограммы яркости окна, в битах
+fn entropy(luma: &[u8], stride: u32, x: u32, y: u32, width: u32, height: u32) -> f64 {
+    let mut histogram = [0u32; 256];
+    for row in y..y + height {
+        let start = (row * stride + x) as usize;
+        for value in &luma[start..start + width as usize] {
+            histogram[usize::from(*value)] += 1;
+        }
+    }
+    let total = f64::from(width * height);
+    histogram
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = f64::from(*count) / total;
+            -p * p.log2()
+        })
+        .sum()
+}
@@ -0,0 +1,112 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::AppError;
+use crate::signing;
+
+// Плотности по умолчанию, если `densities` не передан
+const DEFAULT_DENSITIES: &str = "1,2";
+// Ограничения на список плотностей, чтобы манифест оставался разумным
+const MAX_DENSITIES: usize = 8;
+const MAX_DENSITY: f32 = 4.0;
+
+#[derive(Deserialize)]
+pub struct SrcsetParams {
+    width: u32,
+    height: Option<u32>,
+    url: String,
+    densities: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Candidate {
+    density: f32,
+    width: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    srcset: String,
+    images: Vec<Candidate>,
+}
+
+// Список плотностей вида `1,1.5,2`
+fn parse_densities(value: &str) -> Option<Vec<f32>> {
+    let densities = value
+        .split(',')
+        .map(|item| item.trim().parse::<f32>().ok())
+        .collect::<Option<Vec<f32>>>()?;
+    let valid = !densities.is_empty()
+        && densities.len() <= MAX_DENSITIES
+        && densities
+            .iter()
+            .all(|density| *density > 0.0 && *density <= MAX_DENSITY);
+    valid.then_some(densities)
+}
+
+// Манифест для адаптивных изображений: на каждую плотность ссылка на
+// GET /resize с умноженными width и height (если задана). Остальные параметры запроса
+// передаются как есть, сами изображения создаются при обращении по ссылкам.
+// С SIGNING_SECRET сам запрос должен быть подписан, а ссылки подписываются заново.
+pub async fn srcset(
+    config: web::Data<Config>,
+    query: web::Query<SrcsetParams>,
+    raw: web::Query<Vec<(String, String)>>,
+) -> Result<HttpResponse, AppError> {
+    let densities = parse_densities(query.densities.as_deref().unwrap_or(DEFAULT_DENSITIES))
+        .ok_or(AppError::InvalidParam("Invalid densities"))?;
+    if query.url.is_empty() {
+        return Err(AppError::InvalidUrl);
+    }
+    if query.width == 0 || query.height == Some(0) {
+        return Err(AppError::InvalidParam("Width and height must be positive"));
+    }
+
+    // srcset разрешается относительно страницы, а не этого сервера, поэтому без
+    // PUBLIC_BASE_URL ссылки годятся, только если сервер на том же хосте, что страница.
+    // Host запроса не используется: его задаёт клиент.
+    let base = format!(
+        "{}/resize",
+        config.public_base_url.as_deref().unwrap_or_default()
+    );
+    let passthrough: Vec<&(String, String)> = raw
+        .iter()
+        .filter(|(name, _)| !matches!(name.as_str(), "width" | "height" | "densities" | "sig"))
+        .collect();
+
+    let mut images = Vec::with_capacity(densities.len());
+    for density in densities {
+        let width = (query.width as f32 * density).round().max(1.0) as u32;
+        let height = query
+            .height
+            .map(|height| (height as f32 * density).round().max(1.0) as u32);
+        let mut pairs = vec![("width".to_string(), width.to_string())];
+        if let Some(height) = height {
+            pairs.push(("height".to_string(), height.to_string()));
+        }
+        pairs.extend(passthrough.iter().map(|&pair| pair.clone()));
+        if let Some(secret) = &config.signing_secret {
+            let sig = signing::sign(secret, "/resize", &pairs);
+            pairs.push(("sig".to_string(), sig));
+        }
+        let query = serde_urlencoded::to_string(&pairs)
+            .map_err(|err| AppError::InvalidQuery(err.to_string()))?;
+        images.push(Candidate {
+            density,
+            width,
+            height,
+            url: format!("{base}?{query}"),
+        });
+    }
+
+    let srcset = images
+        .iter()
+        .map(|image| format!("{} {}x", image.url, image.density))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Ok(HttpResponse::Ok().json(Manifest { srcset, images }))
+}
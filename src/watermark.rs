@@ -0,0 +1,123 @@
+use image::{imageops, RgbaImage};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+// Доля ширины результата, которую занимает водяной знак
+const WATERMARK_SCALE: f32 = 0.25;
+// Отступ от края как доля меньшей стороны результата
+const WATERMARK_MARGIN: f32 = 0.02;
+
+// Где разместить водяной знак
+#[derive(Clone, Copy)]
+pub enum Position {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+pub fn parse_position(value: &str) -> Option<Position> {
+    match value.to_ascii_lowercase().as_str() {
+        "top-left" => Some(Position::TopLeft),
+        "top" => Some(Position::Top),
+        "top-right" => Some(Position::TopRight),
+        "left" => Some(Position::Left),
+        "center" => Some(Position::Center),
+        "right" => Some(Position::Right),
+        "bottom-left" => Some(Position::BottomLeft),
+        "bottom" => Some(Position::Bottom),
+        "bottom-right" => Some(Position::BottomRight),
+        _ => None,
+    }
+}
+
+// Водяные знаки, загруженные при старте из WATERMARK_DIR; имя - файл без расширения
+pub struct Watermarks {
+    images: HashMap<String, Arc<RgbaImage>>,
+}
+
+impl Watermarks {
+    pub fn load(dir: Option<&Path>) -> Self {
+        let mut images = HashMap::new();
+        let Some(dir) = dir else {
+            return Watermarks { images };
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::warn!("cannot read watermark directory {}: {err}", dir.display());
+                return Watermarks { images };
+            }
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if !path.is_file() {
+                continue;
+            }
+            match image::open(&path) {
+                Ok(img) => {
+                    images.insert(name.to_string(), Arc::new(img.to_rgba8()));
+                }
+                Err(err) => tracing::warn!("skipping watermark {}: {err}", path.display()),
+            }
+        }
+        tracing::info!("loaded {} watermarks from {}", images.len(), dir.display());
+        Watermarks { images }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<RgbaImage>> {
+        self.images.get(name).cloned()
+    }
+}
+
+// Накладывает водяной знак, масштабированный под размер результата,
+// с прозрачностью `opacity` от 0 до 1
+pub fn apply(img: &mut RgbaImage, mark: &RgbaImage, position: Position, opacity: f32) {
+    let (width, height) = img.dimensions();
+    let (mark_width, mark_height) = mark.dimensions();
+    if mark_width == 0 || mark_height == 0 {
+        return;
+    }
+
+    // Ширина - доля результата, но знак не должен выходить за его высоту
+    let scale = (width as f32 * WATERMARK_SCALE / mark_width as f32)
+        .min(height as f32 / mark_height as f32);
+    let scaled_width = ((mark_width as f32 * scale).round() as u32).max(1);
+    let scaled_height = ((mark_height as f32 * scale).round() as u32).max(1);
+    let mut scaled = imageops::resize(
+        mark,
+        scaled_width,
+        scaled_height,
+        imageops::FilterType::Triangle,
+    );
+    if opacity < 1.0 {
+        for px in scaled.pixels_mut() {
+            px[3] = (f32::from(px[3]) * opacity).round() as u8;
+        }
+    }
+
+    let margin = (width.min(height) as f32 * WATERMARK_MARGIN).round() as i64;
+    let free_x = i64::from(width) - i64::from(scaled_width);
+    let free_y = i64::from(height) - i64::from(scaled_height);
+    let (x, y) = match position {
+        Position::TopLeft => (margin, margin),
+        Position::Top => (free_x / 2, margin),
+        Position::TopRight => (free_x - margin, margin),
+        Position::Left => (margin, free_y / 2),
+        Position::Center => (free_x / 2, free_y / 2),
+        Position::Right => (free_x - margin, free_y / 2),
+        Position::BottomLeft => (margin, free_y - margin),
+        Position::Bottom => (free_x / 2, free_y - margin),
+        Position::BottomRight => (free_x - margin, free_y - margin),
+    };
+    imageops::overlay(img, &scaled, x, y);
+}